@@ -0,0 +1,176 @@
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+};
+
+use ipnetwork::{
+    IpNetwork,
+    Ipv4Network,
+};
+
+use crate::CidrSet;
+
+/// Network of either address family as integer network address and prefix length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Network {
+    address: u128,
+    prefix:  u8,
+}
+
+/// Merges adjacent networks and drops networks subsumed by other ones, so resulting set covers
+/// exactly the same addresses with the fewest CIDRs
+pub fn aggregate(cidrs: &CidrSet) -> CidrSet {
+    let mut v4_networks = Vec::new();
+    let mut v6_networks = Vec::new();
+
+    for cidr in cidrs {
+        match cidr.network() {
+            IpAddr::V4(address) => v4_networks.push(Network {
+                address: u128::from(u32::from(address)),
+                prefix:  cidr.prefix(),
+            }),
+            IpAddr::V6(address) => v6_networks.push(Network {
+                address: u128::from(address),
+                prefix:  cidr.prefix(),
+            }),
+        }
+    }
+
+    let v4_cidrs = aggregate_family(v4_networks, 32)
+        .into_iter()
+        .map(|network| {
+            IpNetwork::new(
+                IpAddr::V4(Ipv4Addr::from(network.address as u32)),
+                network.prefix,
+            )
+        });
+    let v6_cidrs = aggregate_family(v6_networks, 128)
+        .into_iter()
+        .map(|network| IpNetwork::new(IpAddr::V6(Ipv6Addr::from(network.address)), network.prefix));
+
+    v4_cidrs
+        .chain(v6_cidrs)
+        .map(|cidr| cidr.expect("aggregated prefix never exceeds address length"))
+        .collect()
+}
+
+/// Drops IPv4-mapped IPv6 networks (`::ffff:a.b.c.d/p`) whose embedded IPv4 network is already
+/// covered by IPv4 network of `cidrs`
+///
+/// ```
+/// use github_nginx_hooker::{
+///     aggregate::dedup_mapped,
+///     CidrSet,
+/// };
+///
+/// let cidrs: CidrSet = ["192.30.252.0/22", "::ffff:192.30.252.0/118"]
+///     .iter()
+///     .map(|cidr| cidr.parse().unwrap())
+///     .collect();
+///
+/// assert_eq!(dedup_mapped(&cidrs), CidrSet::from_iter(["192.30.252.0/22".parse().unwrap()]));
+/// ```
+pub fn dedup_mapped(cidrs: &CidrSet) -> CidrSet {
+    let is_covered = |embedded: Ipv4Network| {
+        cidrs.iter().any(|cidr| match cidr {
+            IpNetwork::V4(network) => {
+                network.prefix() <= embedded.prefix() && network.contains(embedded.network())
+            }
+            IpNetwork::V6(_) => false,
+        })
+    };
+
+    cidrs
+        .iter()
+        .filter(|cidr| !embedded_ipv4(cidr).is_some_and(is_covered))
+        .copied()
+        .collect()
+}
+
+/// IPv4 network that IPv4-mapped IPv6 `cidr` stands for, `None` for any other network
+fn embedded_ipv4(cidr: &IpNetwork) -> Option<Ipv4Network> {
+    match cidr {
+        IpNetwork::V6(network) if network.prefix() >= 96 => {
+            let address = network.network().to_ipv4_mapped()?;
+            Ipv4Network::new(address, network.prefix() - 96).ok()
+        }
+        _ => None,
+    }
+}
+
+fn aggregate_family(mut networks: Vec<Network>, address_bits: u8) -> Vec<Network> {
+    loop {
+        networks.sort();
+        networks = drop_subsumed(networks, address_bits);
+
+        let mut merged = Vec::with_capacity(networks.len());
+        let mut is_merged = false;
+        let mut index = 0;
+
+        while index < networks.len() {
+            let network = networks[index];
+
+            if let Some(next) = networks.get(index + 1) {
+                if let Some(supernet) = merge_siblings(network, *next, address_bits) {
+                    merged.push(supernet);
+                    is_merged = true;
+                    index += 2;
+                    continue;
+                }
+            }
+
+            merged.push(network);
+            index += 1;
+        }
+
+        networks = merged;
+
+        if !is_merged {
+            return networks;
+        }
+    }
+}
+
+/// Expects `networks` to be sorted by address and then by prefix, so supernet goes first
+fn drop_subsumed(networks: Vec<Network>, address_bits: u8) -> Vec<Network> {
+    let mut kept: Vec<Network> = Vec::with_capacity(networks.len());
+
+    for network in networks {
+        match kept.last() {
+            Some(last)
+                if last_address(*last, address_bits) >= last_address(network, address_bits) => {}
+            _ => kept.push(network),
+        }
+    }
+
+    kept
+}
+
+/// Returns supernet of `first` and `second` if they are two halves of it
+fn merge_siblings(first: Network, second: Network, address_bits: u8) -> Option<Network> {
+    if first.prefix != second.prefix || first.prefix == 0 {
+        return None;
+    }
+
+    let size = 1u128 << (address_bits - first.prefix);
+    let is_first_half = first.address & size == 0;
+
+    if is_first_half && first.address + size == second.address {
+        Some(Network {
+            address: first.address,
+            prefix:  first.prefix - 1,
+        })
+    } else {
+        None
+    }
+}
+
+fn last_address(network: Network, address_bits: u8) -> u128 {
+    let host_bits = u32::from(address_bits - network.prefix);
+
+    network.address
+        | 1u128
+            .checked_shl(host_bits)
+            .map_or(u128::MAX, |size| size - 1)
+}
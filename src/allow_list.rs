@@ -0,0 +1,697 @@
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::{
+        Read,
+        Write,
+    },
+    os::unix::fs::MetadataExt,
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+};
+
+use ipnetwork::IpNetwork;
+
+use crate::{
+    renderer::{
+        renderer_from_config,
+        AllowListRenderer,
+        NginxRenderer,
+    },
+    Config,
+};
+
+pub(crate) const COMMENT_PREFIX: &str = "#";
+
+/// Hasher of CIDR sets, DoS resistant SipHash unless `fast-hash` feature swaps it for FNV, which
+/// is fine for trusted GitHub input
+#[cfg(not(feature = "fast-hash"))]
+pub type CidrHasher = std::collections::hash_map::RandomState;
+/// Hasher of CIDR sets, DoS resistant SipHash unless `fast-hash` feature swaps it for FNV, which
+/// is fine for trusted GitHub input
+#[cfg(feature = "fast-hash")]
+pub type CidrHasher = fnv::FnvBuildHasher;
+/// Set of CIDRs used by allow lists, their diffs and fetched meta information
+pub type CidrSet = HashSet<IpNetwork, CidrHasher>;
+/// Header line with generation timestamp, the only part of allow file that changes on every save
+const GENERATED_AT_PREFIX: &str = "# Generated at ";
+
+/// Changes made to allow list by `AllowList::update`
+#[derive(Debug, Default, Clone)]
+pub struct AllowListDiff {
+    pub added:          CidrSet,
+    pub removed:        CidrSet,
+    /// Allow file was rewritten because its content didn't match configured format
+    pub is_reformatted: bool,
+}
+
+impl AllowListDiff {
+    /// Returns `true` if allow file is unchanged
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && !self.is_reformatted
+    }
+
+    /// Combines changes made to several allow files
+    pub fn merge(&mut self, other: AllowListDiff) {
+        self.added.extend(other.added);
+        self.removed.extend(other.removed);
+        self.is_reformatted |= other.is_reformatted;
+    }
+
+    /// Appends changes made to the same allow file later, CIDR added and then removed (or the
+    /// other way around) cancels out
+    pub fn chain(&mut self, later: AllowListDiff) {
+        for cidr in later.added {
+            if !self.removed.remove(&cidr) {
+                self.added.insert(cidr);
+            }
+        }
+        for cidr in later.removed {
+            if !self.added.remove(&cidr) {
+                self.removed.insert(cidr);
+            }
+        }
+        self.is_reformatted |= later.is_reformatted;
+    }
+}
+
+/// Layout of allow file content
+#[derive(Debug)]
+pub struct AllowFileFormat {
+    /// Serializer of allow list itself
+    pub renderer:        Box<dyn AllowListRenderer>,
+    /// Comment written at the top of allow file followed by generation timestamp
+    pub header:          Option<String>,
+    /// Fail loading allow file with lines that don't match the format instead of rewriting it
+    pub strict:          bool,
+    /// Read allow file back after every save and check it contains what was written
+    pub verify:          bool,
+    /// Write through symlinked allow file to its target instead of replacing the symlink
+    pub follow_symlinks: bool,
+}
+
+impl Default for AllowFileFormat {
+    fn default() -> Self {
+        Self {
+            renderer:        Box::new(NginxRenderer::default()),
+            header:          None,
+            strict:          false,
+            verify:          false,
+            follow_symlinks: true,
+        }
+    }
+}
+
+impl AllowFileFormat {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            renderer:        renderer_from_config(config),
+            header:          config.file_header.clone(),
+            strict:          config.strict_load,
+            verify:          config.verify_writes,
+            follow_symlinks: config.follow_symlinks,
+        }
+    }
+
+    /// Writes `cidrs` in stable order without header
+    pub fn render(&self, cidrs: &CidrSet, writer: &mut dyn Write) -> std::io::Result<()> {
+        self.renderer.render(&sorted_cidrs(cidrs), writer)
+    }
+
+    /// Writes `cidrs` in stable order without header, noting their origin with `tags`
+    pub fn render_tagged(
+        &self,
+        cidrs: &CidrSet,
+        tags: &HashMap<IpNetwork, String>,
+        writer: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        self.renderer
+            .render_tagged(&sorted_cidrs(cidrs), tags, writer)
+    }
+
+    /// Extracts CIDRs from allow file content written in any format, comments are skipped
+    pub fn parse(content: &str) -> CidrSet {
+        significant_lines(content)
+            .flat_map(Self::parse_line)
+            .collect()
+    }
+
+    /// Extracts CIDRs from line written in any format: every word that parses as CIDR
+    pub fn parse_line(line: &str) -> impl Iterator<Item = IpNetwork> + '_ {
+        line.split(|c: char| c.is_whitespace() || ";,\"'{}()=".contains(c))
+            .filter_map(|word| IpNetwork::from_str(word).ok())
+            .map(normalized_cidr)
+    }
+}
+
+/// Nginx allow file and CIDRs it contains
+///
+/// ```
+/// use github_nginx_hooker::{
+///     AllowFileFormat,
+///     AllowList,
+///     CidrSet,
+/// };
+///
+/// let file_path = std::env::temp_dir().join("github_nginx_hooker_doc.conf");
+/// let mut allow_list = AllowList::load(file_path.to_str().unwrap(), AllowFileFormat::default())?;
+///
+/// let cidrs = CidrSet::from_iter(["192.30.252.0/22".parse().unwrap()]);
+/// let diff = allow_list.update(cidrs, false)?;
+///
+/// assert_eq!(allow_list.len(), 1);
+/// assert_eq!(std::fs::read_to_string(&file_path)?, "allow 192.30.252.0/22;\n");
+/// # assert!(!diff.is_empty());
+/// # std::fs::remove_file(&file_path)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct AllowList {
+    file_path:  PathBuf,
+    allow_list: CidrSet,
+    format:     AllowFileFormat,
+    /// Origin of CIDRs written as comments, e.g. GitHub meta categories
+    tags:       HashMap<IpNetwork, String>,
+    /// Tags allow file content was written or read with, file is rewritten when `tags` of its
+    /// CIDRs differ from them
+    file_tags:  HashMap<IpNetwork, String>,
+    /// File content doesn't match the configured format and has to be rewritten on next update
+    is_stale:   bool,
+    /// Missing file is read as empty instead of being created, e.g. for dry run
+    read_only:  bool,
+}
+
+impl AllowList {
+    pub fn load(file_path: &str, format: AllowFileFormat) -> Result<Self, std::io::Error> {
+        // Opening file in missing directory fails with error that doesn't tell which path is wrong
+        if let Some(directory) = parent_directory(Path::new(file_path)) {
+            if !directory.is_dir() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!(
+                        "Directory [{}] of allow file doesn't exist",
+                        directory.display()
+                    ),
+                ));
+            }
+        }
+
+        let mut allow_list = Self {
+            file_path: PathBuf::from(file_path),
+            allow_list: CidrSet::default(),
+            format,
+            tags: HashMap::new(),
+            file_tags: HashMap::new(),
+            is_stale: false,
+            read_only: false,
+        };
+
+        allow_list.reload()?;
+
+        Ok(allow_list)
+    }
+
+    /// Loads allow list without creating the file, missing one is empty. Such allow list is only
+    /// meant for dry run updates
+    pub fn load_read_only(
+        file_path: &str,
+        format: AllowFileFormat,
+    ) -> Result<Self, std::io::Error> {
+        let mut allow_list = Self {
+            file_path: PathBuf::from(file_path),
+            allow_list: CidrSet::default(),
+            format,
+            tags: HashMap::new(),
+            file_tags: HashMap::new(),
+            is_stale: false,
+            read_only: true,
+        };
+
+        allow_list.reload()?;
+
+        Ok(allow_list)
+    }
+
+    /// Re-reads allow list from the file, picking up external edits. Allow file is always
+    /// replaced atomically by `save`, so it is never read half written
+    pub fn reload(&mut self) -> Result<(), std::io::Error> {
+        let mut content = String::new();
+
+        if self.read_only {
+            match File::open(&self.file_path) {
+                Ok(mut file) => {
+                    file.read_to_string(&mut content)?;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        } else {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.file_path)?;
+
+            file.read_to_string(&mut content)?;
+        }
+
+        let mut allow_list = CidrSet::default();
+        let mut content_lines = Vec::new();
+
+        for line in significant_lines(&content) {
+            allow_list.extend(AllowFileFormat::parse_line(line));
+            content_lines.push(line);
+        }
+
+        // Any difference from what would be written, be it another line template, missing
+        // directive or garbage, gets the file rewritten on next update
+        let mut expected_content = Vec::new();
+        self.format.render(&allow_list, &mut expected_content)?;
+        let expected_content = String::from_utf8_lossy(&expected_content);
+
+        if self.format.strict {
+            let expected_lines: HashSet<&str> = significant_lines(&expected_content).collect();
+            let malformed_line = content.lines().enumerate().find(|(_, line)| {
+                significant_lines(line).any(|line| !expected_lines.contains(line))
+            });
+
+            if let Some((index, line)) = malformed_line {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Line {} of allow file [{}] doesn't match configured format: {}",
+                        index + 1,
+                        self.file_path.display(),
+                        line.trim()
+                    ),
+                ));
+            }
+        }
+
+        let is_reformatted = significant_lines(&expected_content).ne(content_lines);
+
+        // Only the first header line is compared, generation timestamp changes on every save
+        let expected_header = self
+            .format
+            .header
+            .as_deref()
+            .and_then(|header| header.lines().next())
+            .map(|header_line| format!("{} {}", COMMENT_PREFIX, header_line));
+        let has_expected_header = match &expected_header {
+            Some(expected_header) => content.lines().next() == Some(expected_header.as_str()),
+            None => true,
+        };
+
+        if is_reformatted {
+            log::debug!(
+                "Allow file [{}] doesn't match configured format and will be rewritten",
+                self.file_path.display()
+            );
+        }
+
+        self.allow_list = allow_list;
+        self.file_tags = parse_tags(&content);
+        self.is_stale = is_reformatted || !has_expected_header;
+
+        Ok(())
+    }
+
+    /// Replaces allow list and saves it if it changed
+    pub fn update(
+        &mut self,
+        new_allow_list: CidrSet,
+        dry_run: bool,
+    ) -> std::io::Result<AllowListDiff> {
+        let new_tags = self.tags_of(&new_allow_list);
+        let is_retagged = new_tags != self.file_tags;
+
+        if self.allow_list == new_allow_list && !self.is_stale && !is_retagged {
+            return Ok(AllowListDiff::default());
+        }
+
+        let added: CidrSet = new_allow_list
+            .difference(&self.allow_list)
+            .copied()
+            .collect();
+        let removed: CidrSet = self
+            .allow_list
+            .difference(&new_allow_list)
+            .copied()
+            .collect();
+
+        if dry_run {
+            for cidr in sorted_cidrs(&added) {
+                log::info!("Would add {}", cidr);
+            }
+            for cidr in sorted_cidrs(&removed) {
+                log::info!("Would remove {}", cidr);
+            }
+        }
+
+        let diff = AllowListDiff {
+            added,
+            removed,
+            is_reformatted: self.is_stale || is_retagged,
+        };
+
+        // Dry run leaves allow list as it is, so later compares still see what is on disk
+        if dry_run {
+            return Ok(diff);
+        }
+
+        let previous_allow_list = std::mem::replace(&mut self.allow_list, new_allow_list);
+
+        // Content is rendered once, thousands of lines are compared and written in one go
+        let content = self.render()?;
+
+        // Rewriting identical content would only bump mtime and wake up file watchers
+        if self.matches_file(&content)? {
+            self.file_tags = new_tags;
+            self.is_stale = false;
+            return Ok(AllowListDiff::default());
+        }
+
+        // Allow list stays as it is on disk, so the same update is retried next time
+        if let Err(err) = self.write_file(&content) {
+            self.allow_list = previous_allow_list;
+            return Err(err);
+        }
+        self.file_tags = new_tags;
+        self.is_stale = false;
+
+        Ok(diff)
+    }
+
+    /// Writes allow list into sibling temporary file and renames it over the allow file, so
+    /// readers never observe partially written allow list
+    pub fn save(&mut self) -> std::io::Result<()> {
+        let content = self.render()?;
+
+        self.write_file(&content)?;
+        self.file_tags = self.tags_of(&self.allow_list);
+
+        Ok(())
+    }
+
+    /// Replaces allow file with already rendered `content`. With `verify` enabled file is read
+    /// back and written once more if it doesn't contain current allow list
+    fn write_file(&self, content: &[u8]) -> std::io::Result<()> {
+        self.replace_file(content)?;
+
+        if !self.format.verify || self.is_written()? {
+            return Ok(());
+        }
+
+        log::error!(
+            "Allow file [{}] doesn't contain allow list that was just written, writing it again",
+            self.file_path.display()
+        );
+        self.replace_file(content)?;
+
+        if self.is_written()? {
+            return Ok(());
+        }
+
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Allow file [{}] doesn't contain allow list that was written to it",
+                self.file_path.display()
+            ),
+        ))
+    }
+
+    /// Returns `true` if CIDRs read back from allow file are exactly current allow list
+    fn is_written(&self) -> std::io::Result<bool> {
+        let content = std::fs::read_to_string(&self.file_path)?;
+
+        Ok(AllowFileFormat::parse(&content) == self.allow_list)
+    }
+
+    /// Replaces allow file with `content` in a single write. Allow file is renamed over only by
+    /// completely written temporary file, otherwise it's left untouched. Permissions and owner of
+    /// existing allow file are kept
+    fn replace_file(&self, content: &[u8]) -> std::io::Result<()> {
+        let target_path = self.write_target()?;
+        let temp_file_path = temp_file_path(&target_path);
+        let target_metadata = std::fs::metadata(&target_path).ok();
+
+        let result = File::create(&temp_file_path).and_then(|mut temp_file| {
+            if let Some(target_metadata) = &target_metadata {
+                copy_owner_and_permissions(&temp_file, target_metadata, &target_path)?;
+            }
+            temp_file.write_all(content)?;
+            temp_file.sync_all()
+        });
+
+        if let Err(err) = result {
+            // Partially written file only takes space that is probably short already
+            let _ = std::fs::remove_file(&temp_file_path);
+            return Err(with_disk_full_hint(err, &self.file_path));
+        }
+
+        std::fs::rename(&temp_file_path, &target_path)
+    }
+
+    /// File renamed over on save: allow file itself or, with `follow_symlinks`, file it points to,
+    /// so the symlink is preserved
+    fn write_target(&self) -> std::io::Result<PathBuf> {
+        let is_symlink = std::fs::symlink_metadata(&self.file_path)
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false);
+        if !is_symlink {
+            return Ok(self.file_path.clone());
+        }
+
+        if self.format.follow_symlinks {
+            return symlink_target(&self.file_path);
+        }
+
+        log::warn!(
+            "Allow file [{}] is a symlink, it's replaced with regular file because \
+             follow_symlinks is disabled",
+            self.file_path.display()
+        );
+
+        Ok(self.file_path.clone())
+    }
+
+    /// Replaces comments noting origin of CIDRs, allow file is rewritten on next update if tags
+    /// of its CIDRs changed
+    pub fn set_tags(&mut self, tags: HashMap<IpNetwork, String>) {
+        self.tags = tags;
+    }
+
+    /// Tags of `cidrs` among current ones
+    fn tags_of(&self, cidrs: &CidrSet) -> HashMap<IpNetwork, String> {
+        self.tags
+            .iter()
+            .filter(|(cidr, _)| cidrs.contains(cidr))
+            .map(|(cidr, tag)| (*cidr, tag.clone()))
+            .collect()
+    }
+
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    pub fn cidrs(&self) -> &CidrSet {
+        &self.allow_list
+    }
+
+    pub fn len(&self) -> usize {
+        self.allow_list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow_list.is_empty()
+    }
+
+    /// Writes allow directives for current allow list into `writer`
+    pub fn write_to(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        self.write_cidrs_to(&self.allow_list, writer)
+    }
+
+    /// Writes allow directives `cidrs` would be saved with into `writer`, e.g. for dry run that
+    /// leaves allow list as it is
+    pub fn write_cidrs_to(&self, cidrs: &CidrSet, writer: &mut dyn Write) -> std::io::Result<()> {
+        if let Some(header) = &self.format.header {
+            for header_line in header.lines() {
+                writer.write_fmt(format_args!("{} {}\n", COMMENT_PREFIX, header_line))?;
+            }
+            writer.write_fmt(format_args!(
+                "{}{}\n",
+                GENERATED_AT_PREFIX,
+                humantime::format_rfc3339_seconds(std::time::SystemTime::now())
+            ))?;
+        }
+
+        self.format.render_tagged(cidrs, &self.tags, writer)
+    }
+
+    /// Allow file content `save` writes
+    fn render(&self) -> std::io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.write_to(&mut content)?;
+
+        Ok(content)
+    }
+
+    /// Returns `true` if allow file already contains exactly `content`, apart from generation
+    /// timestamp
+    fn matches_file(&self, content: &[u8]) -> std::io::Result<bool> {
+        let file_content = match std::fs::read(&self.file_path) {
+            Ok(file_content) => file_content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        Ok(without_timestamp(&file_content).eq(without_timestamp(content)))
+    }
+}
+
+/// Lines of allow file content except generation timestamp
+fn without_timestamp(content: &[u8]) -> impl Iterator<Item = &[u8]> {
+    content
+        .split_inclusive(|byte| *byte == b'\n')
+        .filter(|line| !line.starts_with(GENERATED_AT_PREFIX.as_bytes()))
+}
+
+/// Checks that temporary file used by `AllowList::save` can be created next to `file_path`
+pub fn ensure_writable_directory(file_path: &str) -> std::io::Result<()> {
+    let temp_file_path = temp_file_path(Path::new(file_path));
+
+    File::create(&temp_file_path)?;
+    std::fs::remove_file(&temp_file_path)
+}
+
+/// Trimmed non empty lines with comments stripped
+fn significant_lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .filter_map(|line| line.split(COMMENT_PREFIX).next())
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+}
+
+/// Tags written by `render_tagged` in comments after single CIDR lines
+fn parse_tags(content: &str) -> HashMap<IpNetwork, String> {
+    content
+        .lines()
+        .filter_map(|line| line.split_once(COMMENT_PREFIX))
+        .filter_map(|(code, comment)| {
+            let mut cidrs = AllowFileFormat::parse_line(code);
+            let cidr = cidrs.next()?;
+            let tag = comment.trim();
+
+            (cidrs.next().is_none() && !tag.is_empty()).then(|| (cidr, tag.to_string()))
+        })
+        .collect()
+}
+
+/// Creates missing directories on the way to `file_path`
+pub fn create_parent_directory(file_path: &str) -> std::io::Result<()> {
+    match parent_directory(Path::new(file_path)) {
+        Some(directory) => std::fs::create_dir_all(directory),
+        None => Ok(()),
+    }
+}
+
+/// Gives `temp_file` permissions and owner of `target` it's renamed over, e.g. `0640 root:nginx`.
+/// Only root can give file away, so failure to change owner is only logged
+fn copy_owner_and_permissions(
+    temp_file: &File,
+    target: &std::fs::Metadata,
+    target_path: &Path,
+) -> std::io::Result<()> {
+    temp_file.set_permissions(target.permissions())?;
+
+    let temp_metadata = temp_file.metadata()?;
+    if (temp_metadata.uid(), temp_metadata.gid()) == (target.uid(), target.gid()) {
+        return Ok(());
+    }
+
+    if let Err(err) = std::os::unix::fs::fchown(temp_file, Some(target.uid()), Some(target.gid())) {
+        log::warn!(
+            "Failed to keep owner [{}:{}] of allow file [{}], it's replaced with file owned by \
+             [{}:{}]. {}",
+            target.uid(),
+            target.gid(),
+            target_path.display(),
+            temp_metadata.uid(),
+            temp_metadata.gid(),
+            err
+        );
+    }
+
+    Ok(())
+}
+
+/// Directory containing `file_path`, `None` for bare file names that live in current directory
+fn parent_directory(file_path: &Path) -> Option<&Path> {
+    file_path
+        .parent()
+        .filter(|directory| !directory.as_os_str().is_empty())
+}
+
+/// File symlink `path` finally points to, target of dangling symlink doesn't have to exist
+fn symlink_target(path: &Path) -> std::io::Result<PathBuf> {
+    match std::fs::canonicalize(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let target = std::fs::read_link(path)?;
+            Ok(match parent_directory(path) {
+                Some(directory) if target.is_relative() => directory.join(target),
+                _ => target,
+            })
+        }
+        result => result,
+    }
+}
+
+/// Path of sibling temporary file used to atomically replace `file_path`
+pub(crate) fn temp_file_path(file_path: &Path) -> PathBuf {
+    let mut temp_file_path = file_path.as_os_str().to_owned();
+    temp_file_path.push(".tmp");
+
+    PathBuf::from(temp_file_path)
+}
+
+/// Explains `ENOSPC` raised while writing `file_path`, other errors are returned as is
+fn with_disk_full_hint(err: std::io::Error, file_path: &Path) -> std::io::Error {
+    if err.raw_os_error() != Some(libc::ENOSPC) {
+        return err;
+    }
+
+    std::io::Error::new(
+        err.kind(),
+        format!(
+            "No space left on device while writing [{}], allow file is left untouched. Free some \
+             space on its filesystem",
+            file_path.display()
+        ),
+    )
+}
+
+/// Clears host bits, so `2606:50c0::1/32` and `2606:50c0::/32` compare equal. Textual forms like
+/// letter case or zero compression don't matter once parsed
+pub(crate) fn normalized_cidr(cidr: IpNetwork) -> IpNetwork {
+    IpNetwork::new(cidr.network(), cidr.prefix()).expect("prefix of parsed CIDR is valid")
+}
+
+/// Returns CIDRs in stable order: IPv4 before IPv6, then by network address and prefix
+pub(crate) fn sorted_cidrs(cidrs: &CidrSet) -> Vec<IpNetwork> {
+    let mut sorted_cidrs: Vec<IpNetwork> = cidrs.iter().copied().collect();
+    sorted_cidrs.sort_by_key(|cidr| (cidr.network(), cidr.prefix(), *cidr));
+
+    sorted_cidrs
+}
@@ -0,0 +1,58 @@
+//! Append-only ledger of allow list changes, one JSON object per line
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::SystemTime,
+};
+
+use crate::{
+    allow_list::sorted_cidrs,
+    AllowListDiff,
+};
+
+/// Appends `{"ts": ..., "added": [...], "removed": [...]}` line for `diff` to `audit_log` and
+/// syncs it. With `max_bytes`, audit log that reached it is renamed to `<audit_log>.1` first,
+/// replacing previous one
+pub fn append(
+    audit_log: &Path,
+    max_bytes: Option<u64>,
+    diff: &AllowListDiff,
+) -> std::io::Result<()> {
+    if let Some(max_bytes) = max_bytes {
+        match std::fs::metadata(audit_log) {
+            Ok(metadata) if metadata.len() >= max_bytes => {
+                std::fs::rename(audit_log, rotated_path(audit_log))?;
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut line = serde_json::to_string(&serde_json::json!({
+        "ts": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        "added": sorted_cidrs(&diff.added),
+        "removed": sorted_cidrs(&diff.removed),
+    }))?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(audit_log)?;
+    file.write_all(line.as_bytes())?;
+    file.sync_data()
+}
+
+/// Path audit log is moved to once it reaches `audit_log_max_bytes`
+fn rotated_path(audit_log: &Path) -> PathBuf {
+    let mut rotated_path = audit_log.as_os_str().to_owned();
+    rotated_path.push(".1");
+
+    PathBuf::from(rotated_path)
+}
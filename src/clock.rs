@@ -0,0 +1,76 @@
+//! Source of time for debounce, removal grace periods and sleeps between cycles, so they can be
+//! tested with virtual time instead of real sleeps
+
+use std::{
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration);
+}
+
+/// Real monotonic time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Virtual time that stands still until it's advanced, sleeping advances it right away
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use github_nginx_hooker::clock::{
+///     Clock,
+///     MockClock,
+/// };
+///
+/// let clock = MockClock::default();
+/// let started_at = clock.now();
+///
+/// clock.sleep(Duration::from_secs(3600));
+///
+/// assert_eq!(clock.now() - started_at, Duration::from_secs(3600));
+/// ```
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl MockClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().expect("clock lock is not poisoned") += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("clock lock is not poisoned")
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -0,0 +1,1057 @@
+use std::{
+    collections::HashMap,
+    os::unix::process::{
+        CommandExt,
+        ExitStatusExt,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    Context,
+};
+use ipnetwork::IpNetwork;
+
+use crate::{
+    aggregate,
+    allow_list::sorted_cidrs,
+    audit,
+    clock::{
+        Clock,
+        SystemClock,
+    },
+    create_parent_directory,
+    logging,
+    state::{
+        self,
+        State,
+    },
+    AllowFileFormat,
+    AllowList,
+    AllowListDiff,
+    CidrSet,
+    Config,
+    HookCommand,
+    IpVersion,
+    MetaClient,
+};
+
+/// Allowed allow list shrink per update when `max_shrink_percent` is not configured
+pub(crate) const DEFAULT_MAX_SHRINK_PERCENT: u8 = 50;
+/// Number of CIDRs listed in allow list change log when `log_diff_limit` is not configured
+pub(crate) const DEFAULT_LOG_DIFF_LIMIT: usize = 20;
+/// Private, loopback and link-local ranges GitHub never sends requests from
+const PRIVATE_RANGES: &[&str] = &[
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "fc00::/7",
+    "::1/128",
+    "fe80::/10",
+];
+/// Percentage of the largest allow list ever written that `min_entries` floor is raised to
+const MAX_CIDRS_FLOOR_PERCENT: usize = 90;
+/// Tag of `static_allow` CIDRs written when `tag_categories` is enabled
+const STATIC_ALLOW_TAG: &str = "static";
+/// How often running `after_update_hook` is checked for exit when `hook_timeout_secs` is set
+const HOOK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Everything built from configuration file, rebuilt on configuration reload with runtime state
+/// carried over
+pub struct Hooker {
+    pub config:         Config,
+    meta_client:        MetaClient,
+    allow_lists:        Vec<AllowList>,
+    /// Allow list changes already written but not announced to `after_update_hook` yet
+    pending_hook:       Option<PendingHook>,
+    /// Sidecar file of the first allow file, holding last written allow list
+    state_path:         PathBuf,
+    /// Content of state file, it's rewritten only when allow list differs
+    state:              Option<State>,
+    /// GitHub API was successfully queried since start or configuration reload
+    has_fetched:        bool,
+    /// Categories of fetched CIDRs from last update, removed CIDRs are looked up in it
+    categories_by_cidr: HashMap<IpNetwork, Vec<String>>,
+    /// CIDRs kept in allow lists for `removal_grace_secs` after GitHub stopped returning them,
+    /// with time they were first missing
+    pending_removals:   HashMap<IpNetwork, Instant>,
+    /// Time of hook debounce and removal grace periods
+    clock:              Arc<dyn Clock>,
+}
+
+struct PendingHook {
+    diff:        AllowListDiff,
+    last_change: Instant,
+}
+
+impl Hooker {
+    pub fn from_config_file(config_path: &str) -> Result<Self, anyhow::Error> {
+        let config: Config = Config::read_from_file(config_path)
+            .with_context(|| anyhow!("Failed to read configuration"))?;
+
+        Self::from_config(config)
+    }
+
+    pub fn from_config(config: Config) -> Result<Self, anyhow::Error> {
+        Self::new(config, false)
+    }
+
+    /// Hooker for `update_cycle(true)` only, missing allow files and their directories are not
+    /// created but read as empty
+    pub fn from_config_read_only(config: Config) -> Result<Self, anyhow::Error> {
+        Self::new(config, true)
+    }
+
+    fn new(config: Config, read_only: bool) -> Result<Self, anyhow::Error> {
+        logging::set_format(config.log_format);
+
+        config.ensure_hook_program_exists()?;
+
+        let allow_lists = config
+            .allow_files()
+            .map(|allow_file| {
+                let format = AllowFileFormat::from_config(&config);
+                if read_only {
+                    return AllowList::load_read_only(allow_file, format)
+                        .with_context(|| anyhow!("Failed to load allow list [{}]", allow_file));
+                }
+
+                if config.create_parent_dirs {
+                    create_parent_directory(allow_file).with_context(|| {
+                        anyhow!("Failed to create directory of allow list [{}]", allow_file)
+                    })?;
+                }
+
+                AllowList::load(allow_file, format)
+                    .with_context(|| anyhow!("Failed to load allow list [{}]", allow_file))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let meta_client = MetaClient::from_config(&config)
+            .with_context(|| anyhow!("Failed to create GitHub API client"))?;
+
+        let state_path = state::state_file_path(
+            config
+                .allow_files()
+                .next()
+                .expect("allow file presence is checked by Config::read_from_file"),
+        );
+        // Broken state file only means there is no fallback, it's rewritten after next fetch
+        let state = state::load(&state_path).unwrap_or_else(|err| {
+            log::warn!(
+                "Failed to load state file [{}]. {:#}",
+                state_path.display(),
+                err
+            );
+            None
+        });
+
+        Ok(Self {
+            config,
+            meta_client,
+            allow_lists,
+            pending_hook: None,
+            state_path,
+            state,
+            has_fetched: false,
+            categories_by_cidr: HashMap::new(),
+            pending_removals: HashMap::new(),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Replaces real time, e.g. with `MockClock` in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Carries runtime state of `previous` hooker over configuration reload: meta information
+    /// cache, removal grace timers and categories of fetched CIDRs. Postponed hook of `previous`
+    /// is expected to be run already
+    pub fn with_runtime_state_of(mut self, previous: Hooker) -> Self {
+        self.meta_client.keep_cache_of(previous.meta_client);
+        self.has_fetched = previous.has_fetched;
+        self.categories_by_cidr = previous.categories_by_cidr;
+        self.pending_removals = previous.pending_removals;
+        self
+    }
+
+    /// Number of CIDRs GitHub returned for every configured category in last response
+    pub fn category_counts(&self) -> Vec<(String, usize)> {
+        self.meta_client.category_counts(&self.config.categories)
+    }
+
+    /// Number of CIDRs in allow lists, `allow_file_v6` ones included
+    pub fn allow_list_len(&self) -> usize {
+        allowed_cidrs(&self.allow_lists).len()
+    }
+
+    /// Smallest allow list fetch may produce: `min_entries`, raised to `MAX_CIDRS_FLOOR_PERCENT`
+    /// percent of the largest allow list ever written. `0` if `min_entries` is not configured
+    fn min_entries(&self) -> usize {
+        let min_entries = match self.config.min_entries {
+            Some(min_entries) => min_entries,
+            None => return 0,
+        };
+
+        let max_cidrs = self
+            .state
+            .as_ref()
+            .map_or(0, |state| state.max_cidrs)
+            .max(self.allow_list_len());
+
+        min_entries.max(max_cidrs * MAX_CIDRS_FLOOR_PERCENT / 100)
+    }
+
+    /// Updates allow lists and runs `after_update_hook` unless it is postponed by
+    /// `hook_debounce_secs`. Until GitHub API responds for the first time, allow lists are
+    /// restored from state file on failures
+    pub fn update_cycle(&mut self, dry_run: bool) -> Result<AllowListDiff, anyhow::Error> {
+        let mut github_ips = match fetch_allowed_ips(&mut self.meta_client, &self.config) {
+            Ok(github_ips) => github_ips,
+            Err(err) => {
+                if !self.has_fetched {
+                    self.restore_state(dry_run);
+                }
+                return Err(err);
+            }
+        };
+        self.has_fetched = true;
+        self.keep_pending_removals(&mut github_ips, dry_run);
+
+        log::info!(
+            "Fetched CIDRs per category: {}",
+            self.category_counts()
+                .iter()
+                .map(|(category, count)| format!("{}: {}", category, count))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let mut categories_by_cidr = self.meta_client.categories_by_cidr(&self.config.categories);
+        for cidr in &self.config.static_allow {
+            categories_by_cidr
+                .entry(*cidr)
+                .or_default()
+                .push(STATIC_ALLOW_TAG.to_string());
+        }
+
+        let tags_by_cidr = if self.config.tag_categories {
+            categories_by_cidr.clone()
+        } else {
+            HashMap::new()
+        };
+
+        let min_entries = self.min_entries();
+        let diff = update_allow_lists(
+            &mut self.allow_lists,
+            &self.config,
+            github_ips,
+            &tags_by_cidr,
+            min_entries,
+            dry_run,
+        )?;
+
+        if dry_run {
+            return Ok(diff);
+        }
+
+        let previous_categories_by_cidr =
+            std::mem::replace(&mut self.categories_by_cidr, categories_by_cidr);
+
+        self.save_state();
+        self.append_audit_log(&diff);
+        self.notify_webhook(&diff);
+        let is_hook_relevant = self.is_hook_relevant(&diff, &previous_categories_by_cidr);
+        let is_hook_run = if is_hook_relevant {
+            self.record_change(&diff)?
+        } else {
+            log::info!(
+                "Allow list changes don't touch hook_on_categories, after update hook is not run \
+                 for them"
+            );
+            // Hook postponed for earlier changes may be due already
+            self.record_change(&AllowListDiff::default())?
+        };
+
+        // Hook runs every cycle anyway, so it gets changes postponed by `hook_debounce_secs` and
+        // ones `hook_on_categories` skipped instead of being told nothing changed
+        if self.config.always_run_hook && !is_hook_run {
+            let mut hook_diff = self
+                .pending_hook
+                .take()
+                .map(|pending_hook| pending_hook.diff)
+                .unwrap_or_default();
+            if !is_hook_relevant {
+                hook_diff.chain(diff.clone());
+            }
+            self.execute_hook(&hook_diff, !hook_diff.is_empty())?;
+        }
+
+        Ok(diff)
+    }
+
+    /// Adds CIDRs of allow list GitHub no longer returns to `github_ips` until `removal_grace_secs`
+    /// pass since they went missing, so servers still using old addresses keep working while
+    /// GitHub moves to new ones. Dry run doesn't start or finish grace periods
+    fn keep_pending_removals(&mut self, github_ips: &mut CidrSet, dry_run: bool) {
+        let removal_grace = match self.config.removal_grace_secs {
+            Some(removal_grace_secs) if removal_grace_secs > 0 => {
+                Duration::from_secs(removal_grace_secs)
+            }
+            _ => return,
+        };
+        let allowed_cidrs = allowed_cidrs(&self.allow_lists);
+
+        // Allow list holds aggregated CIDRs, so they're looked up among aggregated fetched ones
+        let fetched_ips = if self.config.aggregate {
+            aggregate::aggregate(github_ips)
+        } else {
+            github_ips.clone()
+        };
+
+        let now = self.clock.now();
+        let mut pending_removals = HashMap::new();
+        // Newly excluded CIDRs and ones of other ip protocol version are removed right away.
+        // Aggregated CIDR holding excluded one would allow it again, so it's removed too
+        let missing_cidrs = allowed_cidrs.difference(&fetched_ips).filter(|cidr| {
+            is_configured(cidr, &self.config)
+                && !self
+                    .config
+                    .exclude
+                    .iter()
+                    .any(|excluded| cidr.contains(excluded.network()))
+        });
+        for cidr in missing_cidrs {
+            let missing_since = match self.pending_removals.get(cidr) {
+                Some(missing_since) => *missing_since,
+                None => {
+                    log::info!(
+                        "CIDR [{}] is no longer returned by GitHub, it's kept for [{}]",
+                        cidr,
+                        humantime::format_duration(removal_grace)
+                    );
+                    now
+                }
+            };
+
+            if now.duration_since(missing_since) < removal_grace {
+                pending_removals.insert(*cidr, missing_since);
+                github_ips.insert(*cidr);
+            }
+        }
+
+        if !dry_run {
+            self.pending_removals = pending_removals;
+        }
+    }
+
+    /// Returns `true` if `diff` should run `after_update_hook`: `hook_on_categories` is not
+    /// configured, allow file is reformatted or added or removed CIDR belongs to one of them.
+    /// Categories of removed CIDRs are looked up in `previous_categories_by_cidr`, CIDRs of
+    /// unknown categories, e.g. removed ones fetched before start, always run the hook
+    fn is_hook_relevant(
+        &self,
+        diff: &AllowListDiff,
+        previous_categories_by_cidr: &HashMap<IpNetwork, Vec<String>>,
+    ) -> bool {
+        let hook_on_categories = match &self.config.hook_on_categories {
+            Some(hook_on_categories) => hook_on_categories,
+            None => return true,
+        };
+        if diff.is_reformatted {
+            return true;
+        }
+
+        let is_relevant = |cidr: &IpNetwork, categories_by_cidr| {
+            let mut categories = covered_categories(cidr, categories_by_cidr).peekable();
+            categories.peek().is_none()
+                || categories.any(|category| hook_on_categories.iter().any(|c| c == category))
+        };
+
+        diff.added
+            .iter()
+            .any(|cidr| is_relevant(cidr, &self.categories_by_cidr))
+            || diff
+                .removed
+                .iter()
+                .any(|cidr| is_relevant(cidr, previous_categories_by_cidr))
+    }
+
+    /// Appends added and removed CIDRs to `audit_log` if allow list changed. Allow files are
+    /// written already, so failure is only logged
+    fn append_audit_log(&self, diff: &AllowListDiff) {
+        let audit_log = match &self.config.audit_log {
+            Some(audit_log) => audit_log,
+            None => return,
+        };
+        if diff.added.is_empty() && diff.removed.is_empty() {
+            return;
+        }
+
+        if let Err(err) = audit::append(Path::new(audit_log), self.config.audit_log_max_bytes, diff)
+        {
+            log::error!(
+                "Failed to append allow list change to audit log [{}]. {:#}",
+                audit_log,
+                err
+            );
+        }
+    }
+
+    /// POSTs added and removed CIDRs to `notify_webhook` if allow list changed. Notification is
+    /// best effort, failure is only logged
+    fn notify_webhook(&self, diff: &AllowListDiff) {
+        let notify_webhook = match &self.config.notify_webhook {
+            Some(notify_webhook) => notify_webhook,
+            None => return,
+        };
+        if diff.added.is_empty() && diff.removed.is_empty() {
+            return;
+        }
+
+        let payload = serde_json::json!({
+            "added": sorted_cidrs(&diff.added),
+            "removed": sorted_cidrs(&diff.removed),
+            "total": self.allow_list_len(),
+        });
+
+        let result = self
+            .meta_client
+            .http_client()
+            .post(notify_webhook)
+            .json(&payload)
+            .send()
+            .and_then(reqwest::blocking::Response::error_for_status);
+
+        match result {
+            Ok(_) => log::debug!("Allow list change is sent to notify_webhook"),
+            // Webhook URLs often contain secret, so it is left out of the error
+            Err(err) => log::warn!("Failed to notify webhook. {}", err.without_url()),
+        }
+    }
+
+    /// Rewrites allow lists from state file with current `line_template`, `exclude`,
+    /// `static_allow` and other settings applied, without asking GitHub, and runs
+    /// `after_update_hook` if they changed. CIDRs of `static_allow` entries removed since state
+    /// file was written stay, as they can't be told apart from GitHub ones
+    pub fn rerender_from_state(&mut self) -> Result<AllowListDiff, anyhow::Error> {
+        let cidrs = match &self.state {
+            Some(state) => state.cidrs.clone(),
+            None => {
+                return Err(anyhow!(
+                    "State file [{}] doesn't exist, there is nothing to re-render allow list from",
+                    self.state_path.display()
+                ))
+            }
+        };
+
+        let mut tags_by_cidr: HashMap<IpNetwork, Vec<String>> = HashMap::new();
+        if self.config.tag_categories {
+            for cidr in &self.config.static_allow {
+                tags_by_cidr.insert(*cidr, vec![STATIC_ALLOW_TAG.to_string()]);
+            }
+        }
+
+        let diff = update_allow_lists(
+            &mut self.allow_lists,
+            &self.config,
+            configured_ips(cidrs, &self.config),
+            &tags_by_cidr,
+            0,
+            false,
+        )?;
+
+        self.save_state();
+        self.append_audit_log(&diff);
+        self.notify_webhook(&diff);
+        self.record_change(&diff)?;
+        self.run_pending_hook()?;
+
+        Ok(diff)
+    }
+
+    /// Writes CIDRs from state file into allow lists, so truncated allow file doesn't stay empty
+    /// while GitHub API is down
+    fn restore_state(&mut self, dry_run: bool) {
+        let cidrs = match &self.state {
+            Some(state) => state.cidrs.clone(),
+            None => return,
+        };
+
+        log::warn!(
+            "GitHub API is not available, using allow list from state file [{}]",
+            self.state_path.display()
+        );
+
+        let result = update_allow_lists(
+            &mut self.allow_lists,
+            &self.config,
+            cidrs,
+            &HashMap::new(),
+            0,
+            dry_run,
+        )
+        .and_then(|diff| {
+            if dry_run {
+                return Ok(());
+            }
+            self.append_audit_log(&diff);
+            self.record_change(&diff).map(|_| ())
+        });
+
+        if let Err(err) = result {
+            log::error!("Failed to restore allow list from state file. {:#}", err);
+        }
+    }
+
+    fn save_state(&mut self) {
+        let cidrs = allowed_cidrs(&self.allow_lists);
+
+        if self.state.as_ref().map(|state| &state.cidrs) == Some(&cidrs) {
+            return;
+        }
+
+        let state = State {
+            cidrs:     cidrs.clone(),
+            max_cidrs: self
+                .state
+                .as_ref()
+                .map_or(0, |state| state.max_cidrs)
+                .max(cidrs.len()),
+        };
+        match state::save(&self.state_path, &state) {
+            Ok(()) => self.state = Some(state),
+            Err(err) => log::warn!(
+                "Failed to save state file [{}]. {:#}",
+                self.state_path.display(),
+                err
+            ),
+        }
+    }
+
+    /// Postpones `after_update_hook` for written changes and runs it once it is due, returns
+    /// `true` if it was run
+    fn record_change(&mut self, diff: &AllowListDiff) -> Result<bool, anyhow::Error> {
+        if !diff.is_empty() {
+            let pending_hook = self.pending_hook.get_or_insert_with(|| PendingHook {
+                diff:        AllowListDiff::default(),
+                last_change: self.clock.now(),
+            });
+            pending_hook.diff.chain(diff.clone());
+            pending_hook.last_change = self.clock.now();
+        }
+
+        if self.hook_due_in() == Some(Duration::ZERO) {
+            return self.take_pending_hook();
+        }
+
+        Ok(false)
+    }
+
+    /// Interval until next check: `repeat`, stretched up to `max_repeat_secs` while GitHub says
+    /// its last response stays fresh longer
+    pub fn poll_interval(&self) -> Duration {
+        let repeat = Duration::from_secs(self.config.repeat);
+        let max_repeat = Duration::from_secs(self.config.max_repeat_secs()).max(repeat);
+
+        match self.meta_client.max_age() {
+            Some(max_age) if max_age > repeat => {
+                let poll_interval = max_age.min(max_repeat);
+                log::debug!(
+                    "GitHub meta information stays fresh for [{}] seconds, next check in [{}] \
+                     seconds",
+                    max_age.as_secs(),
+                    poll_interval.as_secs()
+                );
+                poll_interval
+            }
+            _ => repeat,
+        }
+    }
+
+    /// Time left until postponed `after_update_hook` should be run, `None` if nothing changed
+    pub fn hook_due_in(&self) -> Option<Duration> {
+        let debounce = Duration::from_secs(self.config.hook_debounce_secs.unwrap_or(0));
+
+        self.pending_hook.as_ref().map(|pending_hook| {
+            debounce.saturating_sub(
+                self.clock
+                    .now()
+                    .saturating_duration_since(pending_hook.last_change),
+            )
+        })
+    }
+
+    /// Runs postponed `after_update_hook` right away, e.g. before exit
+    pub fn run_pending_hook(&mut self) -> Result<(), anyhow::Error> {
+        self.take_pending_hook().map(|_| ())
+    }
+
+    /// Runs postponed `after_update_hook` if there is one, returns `true` if it was run
+    fn take_pending_hook(&mut self) -> Result<bool, anyhow::Error> {
+        let pending_hook = match self.pending_hook.take() {
+            Some(pending_hook) => pending_hook,
+            None => return Ok(false),
+        };
+
+        // Flapping CIDRs may cancel each other out during debounce window
+        if pending_hook.diff.is_empty() {
+            log::info!("Allow list changes cancelled each other out, skipping after update hook");
+            return Ok(false);
+        }
+
+        self.execute_hook(&pending_hook.diff, true)?;
+
+        Ok(true)
+    }
+
+    /// Runs `after_update_hook` for `diff`, `changed` is passed to it as `HOOKER_CHANGED`
+    fn execute_hook(&self, diff: &AllowListDiff, changed: bool) -> Result<(), anyhow::Error> {
+        execute_after_update_hook(
+            self.config.hook_shell(),
+            &self.config.after_update_hook,
+            self.config.hook_timeout_secs.map(Duration::from_secs),
+            diff,
+            changed,
+            self.allow_list_len(),
+            hook_stdin(&self.config, diff),
+        )
+        .with_context(|| anyhow!("Failed to execute after update hook"))
+    }
+}
+
+/// Runs `after_update_hook` once as if allow list changed without any CIDRs added or removed, so
+/// hook can be checked without waiting for GitHub to change something. `HOOKER_TOTAL` is the size
+/// of the first allow file as it is on disk
+pub fn test_after_update_hook(config: &Config) -> Result<(), anyhow::Error> {
+    config.ensure_hook_program_exists()?;
+
+    let total = config
+        .allow_files()
+        .next()
+        .and_then(|allow_file| std::fs::read_to_string(allow_file).ok())
+        .map_or(0, |content| AllowFileFormat::parse(&content).len());
+
+    execute_after_update_hook(
+        config.hook_shell(),
+        &config.after_update_hook,
+        config.hook_timeout_secs.map(Duration::from_secs),
+        &AllowListDiff::default(),
+        true,
+        total,
+        hook_stdin(config, &AllowListDiff::default()),
+    )
+    .with_context(|| anyhow!("Failed to execute after update hook"))
+}
+
+/// Updates every allow list, hook is run by caller once no matter how many allow files changed.
+/// CIDRs are tagged with `categories_by_cidr` of fetched CIDRs they cover. Allow lists smaller
+/// than `min_entries` are not written
+fn update_allow_lists(
+    allow_lists: &mut [AllowList],
+    config: &Config,
+    mut github_ips: CidrSet,
+    categories_by_cidr: &HashMap<IpNetwork, Vec<String>>,
+    min_entries: usize,
+    dry_run: bool,
+) -> Result<AllowListDiff, anyhow::Error> {
+    // Shrink is checked after aggregation, truncated response would otherwise be compared by
+    // its raw size with already aggregated allow list. Allow list written before `aggregate`
+    // was enabled is aggregated too, so enabling it isn't taken for shrink
+    let mut current_cidrs = allowed_cidrs(allow_lists);
+    if config.aggregate {
+        github_ips = aggregate::aggregate(&github_ips);
+        current_cidrs = aggregate::aggregate(&current_cidrs);
+    }
+    let current_total = current_cidrs.len();
+    let max_shrink_percent = config
+        .max_shrink_percent
+        .unwrap_or(DEFAULT_MAX_SHRINK_PERCENT);
+    if !is_safe_shrink(current_total, github_ips.len(), max_shrink_percent) {
+        log::warn!(
+            "Refusing to shrink allow list from {} to {} CIDRs (max_shrink_percent is {}), GitHub \
+             API response looks broken. Allow files are left untouched",
+            current_total,
+            github_ips.len(),
+            max_shrink_percent
+        );
+        return Ok(AllowListDiff::default());
+    }
+
+    // Floor is compared after aggregation, that's the size allow lists are written with
+    if github_ips.len() < min_entries {
+        log::error!(
+            "Refusing to write allow list of {} CIDRs, it's below floor of {} CIDRs derived from \
+             min_entries and the largest allow list ever written. GitHub API response may be \
+             truncated. Previous allow list is kept",
+            github_ips.len(),
+            min_entries
+        );
+        return Ok(AllowListDiff::default());
+    }
+
+    let tags = cidr_tags(&github_ips, categories_by_cidr);
+
+    let mut diff = AllowListDiff::default();
+    let mut would_be_cidrs = Vec::new();
+    for allow_list in allow_lists.iter_mut() {
+        allow_list.set_tags(tags.clone());
+        if config.reload_before_compare {
+            allow_list.reload().with_context(|| {
+                anyhow!(
+                    "Failed to reload allow list [{}]",
+                    allow_list.file_path().display()
+                )
+            })?;
+        }
+        let ip_version = config.allow_file_ip_version(allow_list.file_path());
+        let ips: CidrSet = github_ips
+            .iter()
+            .filter(|cidr| ip_version.matches(cidr))
+            .copied()
+            .collect();
+        if dry_run {
+            would_be_cidrs.push(ips.clone());
+        }
+        diff.merge(allow_list.update(ips, dry_run)?);
+    }
+
+    // Dry run logs every CIDR on its own already
+    if !dry_run && !diff.is_empty() {
+        let log_diff_limit = config.log_diff_limit.unwrap_or(DEFAULT_LOG_DIFF_LIMIT);
+        log::info!(
+            "Added CIDRs: {}",
+            truncated_cidrs(&diff.added, log_diff_limit)
+        );
+        log::info!(
+            "Removed CIDRs: {}",
+            truncated_cidrs(&diff.removed, log_diff_limit)
+        );
+    }
+
+    if dry_run {
+        // Allow files get the same content, except for `allow_file_v6` one, so printing the
+        // first allow file and `allow_file_v6` one is enough
+        for (index, (allow_list, cidrs)) in allow_lists.iter().zip(&would_be_cidrs).enumerate() {
+            let is_v6 = matches!(
+                config.allow_file_ip_version(allow_list.file_path()),
+                IpVersion::V6
+            );
+            if index == 0 || is_v6 {
+                allow_list.write_cidrs_to(cidrs, &mut std::io::stdout().lock())?;
+            }
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Union of CIDRs of `allow_lists`, they hold the same CIDRs unless `allow_file_v6` splits them
+fn allowed_cidrs(allow_lists: &[AllowList]) -> CidrSet {
+    allow_lists
+        .iter()
+        .flat_map(|allow_list| allow_list.cidrs().iter().copied())
+        .collect()
+}
+
+/// Joins categories of fetched CIDRs covered by every CIDR, aggregated CIDR gets categories of
+/// all CIDRs it was merged from
+fn cidr_tags(
+    cidrs: &CidrSet,
+    categories_by_cidr: &HashMap<IpNetwork, Vec<String>>,
+) -> HashMap<IpNetwork, String> {
+    cidrs
+        .iter()
+        .filter_map(|cidr| {
+            let mut categories: Vec<&str> = covered_categories(cidr, categories_by_cidr).collect();
+            categories.sort_unstable();
+            categories.dedup();
+
+            (!categories.is_empty()).then(|| (*cidr, categories.join(", ")))
+        })
+        .collect()
+}
+
+/// Categories of fetched CIDRs covered by `cidr`, with duplicates
+fn covered_categories<'a>(
+    cidr: &'a IpNetwork,
+    categories_by_cidr: &'a HashMap<IpNetwork, Vec<String>>,
+) -> impl Iterator<Item = &'a str> {
+    categories_by_cidr
+        .iter()
+        .filter(|(source, _)| cidr.contains(source.network()) && source.prefix() >= cidr.prefix())
+        .flat_map(|(_, categories)| categories.iter().map(String::as_str))
+}
+
+/// Guards against wiping allow list because of GitHub API glitch: new allow list must not be empty
+/// and must keep at least `100 - max_shrink_percent` percent of current one
+fn is_safe_shrink(current_total: usize, new_total: usize, max_shrink_percent: u8) -> bool {
+    if new_total == 0 {
+        return false;
+    }
+
+    let removed = current_total.saturating_sub(new_total);
+
+    removed * 100 <= current_total * usize::from(max_shrink_percent)
+}
+
+/// Fetches ip addresses of configured categories, filters them according to configuration, drops
+/// excluded ones and adds static ones
+pub fn fetch_allowed_ips(
+    meta_client: &mut MetaClient,
+    config: &Config,
+) -> Result<CidrSet, anyhow::Error> {
+    let mut github_ips = meta_client
+        .try_fetch(&config.categories)
+        .with_context(|| anyhow!("Failed to get GitHub ip addresses"))?;
+
+    let private_cidrs: Vec<IpNetwork> = github_ips
+        .iter()
+        .filter(|cidr| !config.exclude.contains(cidr) && is_private(cidr))
+        .copied()
+        .collect();
+    for cidr in private_cidrs {
+        if config.reject_private {
+            log::error!(
+                "GitHub returned private, loopback or link-local CIDR [{}], response may be \
+                 spoofed or mirror misconfigured. CIDR is not written",
+                cidr
+            );
+            github_ips.remove(&cidr);
+        } else {
+            log::warn!(
+                "GitHub returned private, loopback or link-local CIDR [{}], response may be \
+                 spoofed or mirror misconfigured. Set reject_private to keep such CIDRs out",
+                cidr
+            );
+        }
+    }
+
+    for cidr in &config.exclude {
+        if !github_ips.contains(cidr) {
+            log::warn!(
+                "Excluded CIDR [{}] is not returned by GitHub, exclude entry may be stale",
+                cidr
+            );
+        }
+    }
+
+    Ok(configured_ips(github_ips, config))
+}
+
+/// Returns `true` if `cidr` overlaps any of `PRIVATE_RANGES`, e.g. `0.0.0.0/0` does
+fn is_private(cidr: &IpNetwork) -> bool {
+    PRIVATE_RANGES.iter().any(|range| {
+        let range: IpNetwork = range.parse().expect("private ranges are valid CIDRs");
+        range.contains(cidr.network()) || cidr.contains(range.network())
+    })
+}
+
+/// Drops excluded CIDRs and ones of other ip protocol version from `ips` and adds static and
+/// included ones
+fn configured_ips(mut ips: CidrSet, config: &Config) -> CidrSet {
+    ips.retain(|cidr| is_configured(cidr, config));
+
+    ips.extend(config.static_allow.iter().copied());
+    ips.extend(included_ips(config));
+
+    if config.dedup_mapped {
+        let deduplicated_ips = aggregate::dedup_mapped(&ips);
+        if deduplicated_ips.len() < ips.len() {
+            log::info!(
+                "Dropped [{}] IPv4-mapped IPv6 CIDRs already allowed as IPv4 ones",
+                ips.len() - deduplicated_ips.len()
+            );
+        }
+        ips = deduplicated_ips;
+    }
+
+    ips
+}
+
+/// Returns `false` if `cidr` is excluded or of other ip protocol version
+fn is_configured(cidr: &IpNetwork, config: &Config) -> bool {
+    !config.exclude.contains(cidr) && config.ip_version.matches(cidr)
+}
+
+/// CIDRs of `include_files` as they are on disk now, files that can't be read are skipped
+fn included_ips(config: &Config) -> CidrSet {
+    let mut ips = CidrSet::default();
+
+    for include_file in &config.include_files {
+        match std::fs::read_to_string(include_file) {
+            Ok(content) => {
+                let included = AllowFileFormat::parse(&content);
+                log::debug!(
+                    "Included [{}] CIDRs from [{}]",
+                    included.len(),
+                    include_file
+                );
+                ips.extend(included);
+            }
+            Err(err) => log::warn!(
+                "Failed to read include file [{}], it's skipped. Cause: {}",
+                include_file,
+                err
+            ),
+        }
+    }
+
+    ips
+}
+
+/// `{"allow_file": ..., "added": [...], "removed": [...]}` document written to `after_update_hook`
+/// stdin when `hook_stdin` is enabled
+fn hook_stdin(config: &Config, diff: &AllowListDiff) -> Option<Vec<u8>> {
+    if !config.hook_stdin {
+        return None;
+    }
+
+    let document = serde_json::json!({
+        "allow_file": config.allow_files().next(),
+        "added": sorted_cidrs(&diff.added),
+        "removed": sorted_cidrs(&diff.removed),
+    });
+
+    Some(document.to_string().into_bytes())
+}
+
+/// Runs `after_update_hook` through `hook_shell` or directly if it's argv, with `HOOKER_ADDED` and
+/// `HOOKER_REMOVED` environment variables set to space separated CIDRs, `HOOKER_CHANGED` set to
+/// `changed` and `HOOKER_TOTAL` set to allow list size. `stdin` is written to hook stdin, which
+/// is closed afterwards. Hook is killed with all processes it started if it runs longer than
+/// `timeout`
+fn execute_after_update_hook(
+    hook_shell: &str,
+    after_update_hook: &HookCommand,
+    timeout: Option<Duration>,
+    diff: &AllowListDiff,
+    changed: bool,
+    total: usize,
+    stdin: Option<Vec<u8>>,
+) -> Result<(), anyhow::Error> {
+    let mut command = match after_update_hook {
+        HookCommand::Shell(command_line) => {
+            let mut command = std::process::Command::new(hook_shell);
+            command.arg("-c").arg(command_line);
+            command
+        }
+        HookCommand::Argv(argv) => {
+            let mut command = std::process::Command::new(&argv[0]);
+            command.args(&argv[1..]);
+            command
+        }
+    };
+
+    if stdin.is_some() {
+        command.stdin(std::process::Stdio::piped());
+    }
+
+    let mut child = command
+        .env("HOOKER_ADDED", join_cidrs(&diff.added))
+        .env("HOOKER_REMOVED", join_cidrs(&diff.removed))
+        .env("HOOKER_CHANGED", changed.to_string())
+        .env("HOOKER_TOTAL", total.to_string())
+        // Own process group lets timeout kill everything hook started, not just the shell
+        .process_group(0)
+        .spawn()
+        .with_context(|| anyhow!("Failed to run after_update_hook"))?;
+
+    if let (Some(stdin), Some(mut child_stdin)) = (stdin, child.stdin.take()) {
+        // Hook that doesn't read stdin would block writing bigger document forever and timeout
+        // wouldn't be checked, error of such hook closing stdin early doesn't matter either
+        std::thread::spawn(move || {
+            let _ = std::io::Write::write_all(&mut child_stdin, &stdin);
+        });
+    }
+
+    let exit_code = match timeout {
+        Some(timeout) => wait_with_timeout(&mut child, timeout)?,
+        None => child.wait()?,
+    };
+
+    match exit_code.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(anyhow!("after_update_hook exited with code {}", code)),
+        None => match exit_code.signal() {
+            Some(signal) => Err(anyhow!("after_update_hook was killed by signal {}", signal)),
+            None => Err(anyhow!("Failed to get after_update_hook exit code")),
+        },
+    }
+}
+
+/// Waits for `child` to exit, kills its process group and reaps it if `timeout` expires first
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    timeout: Duration,
+) -> Result<std::process::ExitStatus, anyhow::Error> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(exit_code) = child.try_wait()? {
+            return Ok(exit_code);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+
+        std::thread::sleep(HOOK_POLL_INTERVAL.min(deadline - now));
+    }
+
+    // Child is the process group leader, so its pid is the group id
+    // SAFETY: kill has no memory safety preconditions
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    // Reaping the child prevents zombie, killed grandchildren are reaped by init
+    child.wait()?;
+
+    Err(anyhow!(
+        "after_update_hook didn't finish in {} and was killed",
+        humantime::format_duration(timeout)
+    ))
+}
+
+/// Comma separated first `limit` CIDRs in stable order, followed by number of omitted ones
+fn truncated_cidrs(cidrs: &CidrSet, limit: usize) -> String {
+    if cidrs.is_empty() {
+        return String::from("none");
+    }
+
+    let sorted_cidrs = sorted_cidrs(cidrs);
+    let mut listed = sorted_cidrs
+        .iter()
+        .take(limit)
+        .map(IpNetwork::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if sorted_cidrs.len() > limit {
+        listed.push_str(&format!(" ... and {} more", sorted_cidrs.len() - limit));
+    }
+
+    listed
+}
+
+fn join_cidrs(cidrs: &CidrSet) -> String {
+    sorted_cidrs(cidrs)
+        .iter()
+        .map(IpNetwork::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
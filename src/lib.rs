@@ -0,0 +1,60 @@
+//! Keeps Nginx allow list in sync with ip addresses published by GitHub meta API
+//!
+//! The `github-nginx-hooker` binary is a thin wrapper around [`Hooker`], the pieces it is built
+//! from can be used on their own: [`fetch_meta`] to get GitHub ip addresses and [`AllowList`] to
+//! keep allow file up to date
+
+mod allow_list;
+mod audit;
+mod config;
+mod hooker;
+mod meta;
+mod renderer;
+mod state;
+
+pub mod aggregate;
+pub mod clock;
+pub mod lock;
+pub mod logging;
+pub mod metrics;
+pub mod systemd;
+
+pub use crate::{
+    allow_list::{
+        create_parent_directory,
+        ensure_writable_directory,
+        AllowFileFormat,
+        AllowList,
+        AllowListDiff,
+        CidrHasher,
+        CidrSet,
+    },
+    config::{
+        AuthScheme,
+        Config,
+        ConfigReadError,
+        HookCommand,
+        IpVersion,
+        META_CATEGORIES,
+        STDIN_CONFIG_PATH,
+    },
+    hooker::{
+        fetch_allowed_ips,
+        test_after_update_hook,
+        Hooker,
+    },
+    meta::{
+        fetch_meta,
+        FetchError,
+        MetaClient,
+    },
+    renderer::{
+        AllowListRenderer,
+        IpsetRenderer,
+        NftablesRenderer,
+        NginxGeoRenderer,
+        NginxRenderer,
+        OutputFormat,
+        PlainRenderer,
+    },
+};
@@ -0,0 +1,115 @@
+//! Advisory lock that keeps two instances from writing the same allow file and PID file for
+//! classic init setups
+
+use std::{
+    fs::File,
+    os::unix::io::AsRawFd,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    Context,
+};
+
+/// Exclusive `flock` on lock file, released when dropped or when process exits
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    /// Locks `path` without waiting, fails if another process holds the lock
+    pub fn acquire(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = File::create(path)
+            .with_context(|| anyhow!("Failed to create lock file [{}]", path.display()))?;
+
+        // SAFETY: flock only takes descriptor of file that stays open for the call
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                return Err(anyhow!(
+                    "Lock file [{}] is held by another instance, is github-nginx-hooker already \
+                     running for the same allow file?",
+                    path.display()
+                ));
+            }
+            return Err(err).with_context(|| anyhow!("Failed to lock [{}]", path.display()));
+        }
+
+        log::debug!("Acquired lock file [{}]", path.display());
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// File holding PID of running daemon, removed when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes PID of current process to `path`, fails if it names another live process. PID file
+    /// left by process that is gone, e.g. killed one, is overwritten
+    pub fn create(path: &Path) -> Result<Self, anyhow::Error> {
+        let pid = std::process::id();
+
+        match std::fs::read_to_string(path) {
+            Ok(content) => match content.trim().parse::<u32>() {
+                Ok(file_pid) if file_pid != pid && is_alive(file_pid) => {
+                    return Err(anyhow!(
+                        "PID file [{}] names running process [{}], is github-nginx-hooker already \
+                         running?",
+                        path.display(),
+                        file_pid
+                    ));
+                }
+                _ => log::warn!("Overwriting stale PID file [{}]", path.display()),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| anyhow!("Failed to read PID file [{}]", path.display()))
+            }
+        }
+
+        std::fs::write(path, format!("{}\n", pid))
+            .with_context(|| anyhow!("Failed to write PID file [{}]", path.display()))?;
+
+        log::debug!("Wrote PID [{}] to [{}]", pid, path.display());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::warn!(
+                "Failed to remove PID file [{}], cause: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Returns `true` if process `pid` exists, even if it belongs to another user
+fn is_alive(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+
+    // Signal 0 only checks that process exists and may be signalled
+    // SAFETY: kill has no memory safety preconditions
+    let is_signalled = unsafe { libc::kill(pid, 0) } == 0;
+
+    is_signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
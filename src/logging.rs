@@ -0,0 +1,80 @@
+use std::{
+    io::Write,
+    sync::atomic::{
+        AtomicBool,
+        Ordering,
+    },
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// Whether log records are written as JSON, switched after configuration is read
+static IS_JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default, Clone, Copy)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable `env_logger` output
+    #[default]
+    Text,
+    /// One JSON object per line with `level`, `ts`, `msg` and record key values
+    Json,
+}
+
+/// Initializes `env_logger` in text format with `level` unless `RUST_LOG` is set, use
+/// `set_format` to switch to JSON
+pub fn init(level: log::LevelFilter) {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level.as_str()))
+        .format(|buf, record| {
+            if !IS_JSON.load(Ordering::Relaxed) {
+                // Same layout as `env_logger` default format
+                return writeln!(
+                    buf,
+                    "[{} {:<5} {}] {}",
+                    buf.timestamp(),
+                    buf.default_styled_level(record.level()),
+                    record.target(),
+                    record.args()
+                );
+            }
+
+            let mut object = serde_json::Map::new();
+            object.insert("level".into(), record.level().as_str().into());
+            object.insert("ts".into(), buf.timestamp().to_string().into());
+            object.insert("target".into(), record.target().into());
+            object.insert("msg".into(), record.args().to_string().into());
+
+            let mut visitor = JsonVisitor(&mut object);
+            // Visitor never fails
+            let _ = record.key_values().visit(&mut visitor);
+
+            writeln!(buf, "{}", serde_json::Value::Object(object))
+        })
+        .init();
+}
+
+pub fn set_format(log_format: LogFormat) {
+    IS_JSON.store(matches!(log_format, LogFormat::Json), Ordering::Relaxed);
+}
+
+struct JsonVisitor<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> log::kv::Visitor<'kvs> for JsonVisitor<'_> {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        let value = match value.to_u64() {
+            Some(number) => number.into(),
+            None => value.to_string().into(),
+        };
+        self.0.insert(key.as_str().into(), value);
+
+        Ok(())
+    }
+}
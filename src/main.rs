@@ -1,15 +1,9 @@
-use std::{
-    collections::HashSet,
-    fs::{
-        File,
-        OpenOptions,
+use std::sync::{
+    atomic::{
+        AtomicBool,
+        Ordering,
     },
-    io::{
-        Read,
-        Seek,
-        Write,
-    },
-    str::FromStr,
+    Arc,
 };
 
 use anyhow::{
@@ -17,228 +11,793 @@ use anyhow::{
     Context,
 };
 use clap::Parser;
-use ipnetwork::IpNetwork;
-use serde::Deserialize;
-
-const GITHUB_API_META_URL: &str = "https://api.github.com/meta";
-const ACCEPT_HEADER_VALUE: &str = "application/vnd.github+json";
+use github_nginx_hooker::{
+    aggregate,
+    clock::{
+        Clock,
+        SystemClock,
+    },
+    ensure_writable_directory,
+    fetch_allowed_ips,
+    lock,
+    logging,
+    metrics,
+    systemd,
+    test_after_update_hook,
+    AllowFileFormat,
+    AllowListDiff,
+    CidrSet,
+    Config,
+    FetchError,
+    Hooker,
+    MetaClient,
+    STDIN_CONFIG_PATH,
+};
+use rand::Rng;
+
+/// Crate version and git hash of the build
+const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("GIT_HASH"), ")");
+/// How often sleep between cycles checks whether a signal was received
+const SIGNAL_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+/// Relative random deviation applied to backoff duration
+const BACKOFF_JITTER: f64 = 0.1;
+/// Time systemd is asked to wait for startup on top of `startup_delay_secs`, the first check has
+/// to finish in it
+const STARTUP_TIMEOUT_HEADROOM: std::time::Duration = std::time::Duration::from_secs(90);
+/// ANSI escape sequences coloring `diff` output on terminal
+const COLOR_ADDED: &str = "\x1b[32m";
+const COLOR_REMOVED: &str = "\x1b[31m";
+const COLOR_RESET: &str = "\x1b[0m";
 
 #[derive(Debug)]
 #[derive(Parser)]
+#[clap(version = VERSION)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Path to config file
-    config: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(flatten)]
+    run:     RunArgs,
+    /// Log more, repeat for more details: -v info, -vv debug, -vvv trace. RUST_LOG overrides it
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
 }
 
-#[derive(Deserialize)]
-struct Config {
-    /// GitHub API token
-    pub token:             String,
-    /// Path to file where Nginx allow list show be written
-    pub allow_file:        String,
-    /// Time interval in seconds between checks
-    pub repeat:            u64,
-    /// Command to execute after allow lsit change
-    pub after_update_hook: String,
+#[derive(Debug)]
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Check configuration, allow file directory and GitHub token without writing anything
+    Validate {
+        /// Path to config file, `-` reads it from stdin
+        config: String,
+    },
+    /// Check whether ip address is in current allow list, exit code is non zero if it's not
+    Explain {
+        /// Path to config file, `-` reads it from stdin
+        config: String,
+        /// Ip address to look up
+        ip:     std::net::IpAddr,
+    },
+    /// Print CIDRs GitHub would add to and remove from allow files without writing anything
+    Diff {
+        /// Path to config file, `-` reads it from stdin
+        config: String,
+    },
+    /// Rewrite allow files from state file with current configuration and run after_update_hook
+    /// if they changed, without asking GitHub
+    Reload {
+        /// Path to config file, `-` reads it from stdin
+        config: String,
+    },
 }
 
-impl Config {
-    pub fn read_from_file(file_path: &str) -> Result<Self, ConfigReadError> {
-        let file_content = std::fs::read_to_string(file_path)?;
+#[derive(Debug)]
+#[derive(clap::Args)]
+struct RunArgs {
+    /// Path to config file, `-` reads it from stdin
+    #[clap(required = true)]
+    config:       Option<String>,
+    /// Fetch once and print the would-be allow list without writing it or running the hook
+    #[clap(long)]
+    dry_run:      bool,
+    /// Run single update cycle and exit, exit code is non zero if it failed
+    #[clap(long)]
+    once:         bool,
+    /// Print configuration in effect with defaults filled in and secrets redacted, then exit
+    #[clap(long)]
+    print_config: bool,
+    /// Run after_update_hook once without fetching anything, exit code is non zero if it failed
+    #[clap(long)]
+    test_hook:    bool,
+}
 
-        let config = toml::from_str(&file_content)?;
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
 
-        Ok(config)
+    logging::init(match args.verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    });
+
+    match args.command {
+        Some(Command::Validate { config }) => validate(&config),
+        Some(Command::Explain { config, ip }) => explain(&config, ip),
+        Some(Command::Diff { config }) => diff(&config),
+        Some(Command::Reload { config }) => reload(&config),
+        None => run(args.run),
     }
 }
 
-#[derive(Debug)]
-#[derive(thiserror::Error)]
-enum ConfigReadError {
-    #[error("{0}")]
-    Io(#[from] std::io::Error),
-    #[error("{0}")]
-    Parse(#[from] toml::de::Error),
-}
+/// Checks everything needed for update cycle to succeed without writing allow file or running
+/// after update hook
+fn validate(config_path: &str) -> Result<(), anyhow::Error> {
+    let config: Config = Config::read_from_file(config_path)
+        .with_context(|| anyhow!("Failed to read configuration"))?;
 
-#[derive(Deserialize)]
-struct MetaInfo {
-    pub hooks: Vec<IpNetwork>,
-}
+    logging::set_format(config.log_format);
 
-#[derive(Debug)]
-struct AllowList {
-    file_handler: File,
-    allow_list:   HashSet<IpNetwork>,
-}
+    config.ensure_hook_program_exists()?;
 
-impl AllowList {
-    pub fn load(file_path: &str) -> Result<Self, std::io::Error> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(file_path)?;
+    for allow_file in config.allow_files() {
+        if config.writes_to_stdout() {
+            continue;
+        }
 
-        let mut content = String::new();
+        let has_directory = std::path::Path::new(allow_file)
+            .parent()
+            .is_none_or(|directory| directory.as_os_str().is_empty() || directory.is_dir());
+        if !has_directory && config.create_parent_dirs {
+            log::info!(
+                "Allow file [{}] directory doesn't exist and will be created",
+                allow_file
+            );
+            continue;
+        }
 
-        file.read_to_string(&mut content)?;
+        ensure_writable_directory(allow_file)
+            .with_context(|| anyhow!("Allow file [{}] directory is not writable", allow_file))?;
+    }
 
-        let mut allow_list = HashSet::new();
+    let mut meta_client = MetaClient::from_config(&config)
+        .with_context(|| anyhow!("Failed to create GitHub API client"))?;
 
-        for (line_number, line) in content.lines().enumerate() {
-            let line = line.replace(';', "");
-            let line = line.replace("allow ", "");
-            let line = line.trim();
+    let mut github_ips = fetch_allowed_ips(&mut meta_client, &config)?;
+    if config.aggregate {
+        github_ips = aggregate::aggregate(&github_ips);
+    }
 
-            if line.is_empty() {
-                continue;
-            }
+    println!(
+        "Configuration is valid, {} CIDRs would be written to {}",
+        github_ips.len(),
+        config.allow_files().collect::<Vec<_>>().join(", ")
+    );
 
-            let cidr = match IpNetwork::from_str(line) {
-                Ok(cidr) => cidr,
-                Err(err) => {
-                    log::error!(
-                        "Failed to parse CIDR [{}] at line {}, cause: {:#}, skipping rest of the \
-                         file",
-                        line,
-                        line_number,
-                        err
-                    );
-                    allow_list.clear();
-                    break;
-                }
-            };
+    Ok(())
+}
+
+/// Looks `ip` up in allow files as they are on disk, without asking GitHub
+fn explain(config_path: &str, ip: std::net::IpAddr) -> Result<(), anyhow::Error> {
+    let config: Config = Config::read_from_file(config_path)
+        .with_context(|| anyhow!("Failed to read configuration"))?;
+
+    let mut is_allowed = false;
+
+    for allow_file in config.allow_files() {
+        let content = std::fs::read_to_string(allow_file)
+            .with_context(|| anyhow!("Failed to read allow file [{}]", allow_file))?;
 
-            allow_list.insert(cidr);
+        let mut matching_cidrs: Vec<_> = AllowFileFormat::parse(&content)
+            .into_iter()
+            .filter(|cidr| cidr.contains(ip))
+            .collect();
+        matching_cidrs.sort_by_key(|cidr| std::cmp::Reverse(cidr.prefix()));
+
+        match matching_cidrs.first() {
+            Some(cidr) => {
+                println!("{} is allowed by {} in {}", ip, cidr, allow_file);
+                is_allowed = true;
+            }
+            None => println!("{} is not allowed in {}", ip, allow_file),
         }
+    }
 
-        Ok(Self {
-            file_handler: file,
-            allow_list,
-        })
+    if !is_allowed {
+        return Err(anyhow!("{} is not in allow list", ip));
+    }
+
+    Ok(())
+}
+
+/// Compares allow files as they are on disk with what GitHub returns now, missing allow file is
+/// compared as empty one
+fn diff(config_path: &str) -> Result<(), anyhow::Error> {
+    let config: Config = Config::read_from_file(config_path)
+        .with_context(|| anyhow!("Failed to read configuration"))?;
+
+    logging::set_format(config.log_format);
+
+    let mut meta_client = MetaClient::from_config(&config)
+        .with_context(|| anyhow!("Failed to create GitHub API client"))?;
+
+    let mut github_ips = fetch_allowed_ips(&mut meta_client, &config)?;
+    if config.aggregate {
+        github_ips = aggregate::aggregate(&github_ips);
     }
 
-    pub fn update(&mut self, new_allow_list: HashSet<IpNetwork>) -> std::io::Result<bool> {
-        if self.allow_list != new_allow_list {
-            self.allow_list = new_allow_list;
-            self.save()?;
-            Ok(true)
+    let is_terminal = std::io::IsTerminal::is_terminal(&std::io::stdout());
+    let colored = |color: &str, line: String| {
+        if is_terminal {
+            format!("{}{}{}", color, line, COLOR_RESET)
         } else {
-            Ok(false)
+            line
+        }
+    };
+
+    for allow_file in config.allow_files() {
+        let content = match std::fs::read_to_string(allow_file) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| anyhow!("Failed to read allow file [{}]", allow_file))
+            }
+        };
+        let current_ips = AllowFileFormat::parse(&content);
+        let ip_version = config.allow_file_ip_version(std::path::Path::new(allow_file));
+        let github_ips: CidrSet = github_ips
+            .iter()
+            .filter(|cidr| ip_version.matches(cidr))
+            .copied()
+            .collect();
+
+        let mut changes: Vec<_> = github_ips
+            .difference(&current_ips)
+            .map(|cidr| (cidr, '+'))
+            .chain(current_ips.difference(&github_ips).map(|cidr| (cidr, '-')))
+            .collect();
+        changes.sort_by_key(|(cidr, _)| (cidr.network(), cidr.prefix()));
+
+        println!("--- {}", allow_file);
+        println!("+++ GitHub meta");
+        if changes.is_empty() {
+            println!("  allow list is up to date");
+        }
+        for (cidr, sign) in changes {
+            let color = if sign == '+' {
+                COLOR_ADDED
+            } else {
+                COLOR_REMOVED
+            };
+            println!("{}", colored(color, format!("{}{}", sign, cidr)));
         }
     }
 
-    pub fn save(&mut self) -> std::io::Result<()> {
-        self.file_handler.set_len(0)?;
-        self.file_handler.seek(std::io::SeekFrom::Start(0))?;
+    Ok(())
+}
 
-        for cidr in &self.allow_list {
-            self.file_handler
-                .write_fmt(format_args!("allow {};\n", cidr))?;
-        }
+/// Re-renders allow files from last written allow list, e.g. after `line_template` or
+/// `static_allow` change
+fn reload(config_path: &str) -> Result<(), anyhow::Error> {
+    let config: Config = Config::read_from_file(config_path)
+        .with_context(|| anyhow!("Failed to read configuration"))?;
 
-        Ok(())
+    // Allow list printed to stdout has no allow file or state file to re-render from
+    if config.writes_to_stdout() {
+        return Err(anyhow!(
+            "allow_file \"-\" (stdout) has no allow files to reload, run without reload to print \
+             allow list"
+        ));
     }
+
+    let _instance_lock = acquire_instance_lock(&config)?;
+    let mut hooker = Hooker::from_config(config)?;
+
+    let diff = hooker.rerender_from_state()?;
+
+    if diff.is_empty() {
+        println!("Allow files are up to date");
+    } else {
+        println!(
+            "Allow files are rewritten, {} added, {} removed",
+            diff.added.len(),
+            diff.removed.len()
+        );
+    }
+
+    Ok(())
 }
 
-#[derive(Debug)]
-#[derive(thiserror::Error)]
-enum AllowFileLoadError {
-    #[error("{0}")]
-    Io(#[from] std::io::Error),
-    #[error("{0}")]
-    Parse(#[from] ipnetwork::IpNetworkError),
+fn print_config(config_path: &str) -> Result<(), anyhow::Error> {
+    let config: Config = Config::read_from_file(config_path)
+        .with_context(|| anyhow!("Failed to read configuration"))?;
+
+    let effective_config = toml::to_string(&config.effective())
+        .with_context(|| anyhow!("Failed to serialize configuration"))?;
+
+    print!("{}", effective_config);
+
+    Ok(())
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
+/// Fetches allow list once and prints it to stdout without touching any files
+fn print_allow_list(config: &Config) -> Result<(), anyhow::Error> {
+    logging::set_format(config.log_format);
+
+    let mut meta_client = MetaClient::from_config(config)
+        .with_context(|| anyhow!("Failed to create GitHub API client"))?;
+
+    let mut github_ips = fetch_allowed_ips(&mut meta_client, config)?;
+    if config.aggregate {
+        github_ips = aggregate::aggregate(&github_ips);
+    }
 
-    env_logger::init();
+    AllowFileFormat::from_config(config)
+        .render(&github_ips, &mut std::io::stdout().lock())
+        .with_context(|| anyhow!("Failed to print allow list"))
+}
+
+fn run(args: RunArgs) -> Result<(), anyhow::Error> {
+    let config_path = args
+        .config
+        .expect("config is required when no subcommand is given");
 
-    let config: Config = Config::read_from_file(&args.config)
+    if args.print_config {
+        return print_config(&config_path);
+    }
+
+    let config: Config = Config::read_from_file(&config_path)
         .with_context(|| anyhow!("Failed to read configuration"))?;
 
-    let mut allow_file = AllowList::load(&config.allow_file)
-        .with_context(|| anyhow!("Failed to load allow list"))?;
+    if args.test_hook {
+        logging::set_format(config.log_format);
+        test_after_update_hook(&config)?;
+        println!("after_update_hook succeeded");
+        return Ok(());
+    }
 
-    let authorization_header_value = format!("token {}", config.token);
+    if config.writes_to_stdout() {
+        return print_allow_list(&config);
+    }
 
-    loop {
-        match update_cycle(
-            &authorization_header_value,
-            &mut allow_file,
-            &config.after_update_hook,
-        ) {
-            Ok(is_changed) => {
-                log::info!("Update cycle completed");
-                if is_changed {
-                    log::info!("Allow list is CHANGED");
+    // Dry run doesn't write anything, so it may run next to running instance
+    let mut instance_lock = if args.dry_run {
+        None
+    } else {
+        Some(acquire_instance_lock(&config)?)
+    };
+
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let hooker = if args.dry_run {
+        Hooker::from_config_read_only(config)?
+    } else {
+        Hooker::from_config(config)?
+    };
+    let mut hooker = hooker.with_clock(Arc::clone(&clock));
+
+    let startup_delay = random_delay(hooker.config.startup_delay_secs.unwrap_or(0));
+    if !startup_delay.is_zero() && !args.dry_run {
+        log::info!(
+            "Waiting [{:.1}] seconds before the first check",
+            startup_delay.as_secs_f64()
+        );
+    }
+
+    if args.dry_run || args.once {
+        // Dry run is run by hand, nobody wants to wait for it
+        if args.once {
+            clock.sleep(startup_delay);
+        }
+        let started_at = clock.now();
+        let diff = hooker.update_cycle(args.dry_run);
+        log_cycle_summary(
+            diff.as_ref().ok(),
+            hooker.allow_list_len(),
+            clock.now() - started_at,
+        );
+        let diff = diff?;
+        log_cycle_result(&diff);
+        // There is no next cycle to wait for, so hook_debounce_secs is not applied
+        return hooker.run_pending_hook();
+    }
+
+    // Written by daemon only, one-off runs are not something init system tracks
+    let mut pid_file = match &hooker.config.pid_file {
+        Some(pid_file) => Some(lock::PidFile::create(std::path::Path::new(pid_file))?),
+        None => None,
+    };
+
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload_requested))
+        .with_context(|| anyhow!("Failed to register SIGHUP handler"))?;
+
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        // Second signal terminates immediately in case current cycle got stuck
+        signal_hook::flag::register_conditional_shutdown(
+            signal,
+            1,
+            Arc::clone(&shutdown_requested),
+        )
+        .and_then(|_| signal_hook::flag::register(signal, Arc::clone(&shutdown_requested)))
+        .with_context(|| anyhow!("Failed to register signal {} handler", signal))?;
+    }
+
+    let metrics = Arc::new(metrics::Metrics::default());
+    if let Some(metrics_listen) = &hooker.config.metrics_listen {
+        metrics::spawn_server(metrics_listen, Arc::clone(&metrics))
+            .with_context(|| anyhow!("Failed to start metrics server on [{}]", metrics_listen))?;
+    }
+    if let Some(health_listen) = &hooker.config.health_listen {
+        metrics::spawn_health_server(health_listen, Arc::clone(&metrics)).with_context(|| {
+            anyhow!("Failed to start health check server on [{}]", health_listen)
+        })?;
+    }
+
+    metrics.record_startup_delay(startup_delay);
+    systemd::extend_startup_timeout(startup_delay + STARTUP_TIMEOUT_HEADROOM);
+    sleep_until_signal(&*clock, startup_delay, &[&shutdown_requested]);
+
+    let mut consecutive_failures = 0;
+    let mut is_ready = false;
+    // Postponed hook may wake the loop up before it's time to fetch again
+    let mut next_fetch_at = clock.now();
+
+    while !shutdown_requested.load(Ordering::Relaxed) {
+        if reload_requested.swap(false, Ordering::Relaxed) {
+            // New configuration is applied by fetching right away
+            next_fetch_at = clock.now();
+            if config_path == STDIN_CONFIG_PATH {
+                log::warn!("Configuration read from stdin can't be reloaded, restart to change it");
+            } else {
+                // Allow files of new configuration are touched only once its lock is held
+                let reloaded_hooker = Config::read_from_file(&config_path)
+                    .with_context(|| anyhow!("Failed to read configuration"))
+                    .and_then(|config| {
+                        let locks = ReloadedLocks::take(&hooker.config, &config)?;
+                        let reloaded = Hooker::from_config(config)?;
+                        locks.replace(&mut instance_lock, &mut pid_file);
+                        Ok(reloaded)
+                    });
+                match reloaded_hooker {
+                    Ok(reloaded_hooker) => {
+                        run_pending_hook(&mut hooker);
+                        warn_about_unreloadable_listeners(&hooker.config, &reloaded_hooker.config);
+                        hooker = reloaded_hooker
+                            .with_clock(Arc::clone(&clock))
+                            .with_runtime_state_of(hooker);
+                        log::info!("Configuration reloaded");
+                    }
+                    Err(err) => log::error!(
+                        "Configuration reload failed, keeping previous configuration. {:#}",
+                        err
+                    ),
+                }
+            }
+        }
+
+        // Only postponed hook is due, fetching now would spend rate limit for nothing
+        if clock.now() < next_fetch_at {
+            if hooker.hook_due_in() == Some(std::time::Duration::ZERO) {
+                run_pending_hook(&mut hooker);
+            }
+            sleep_until_next_wake(
+                &*clock,
+                &hooker,
+                next_fetch_at,
+                &[&shutdown_requested, &reload_requested],
+            );
+            continue;
+        }
+
+        let repeat = hooker.config.repeat;
+        let started_at = clock.now();
+        let result = hooker.update_cycle(false);
+        log_cycle_summary(
+            result.as_ref().ok(),
+            hooker.allow_list_len(),
+            clock.now() - started_at,
+        );
+        // Health check deadline moves only with cycles that keep schedule, failures retried with
+        // backoff let it pass
+        let (sleep_duration, is_on_schedule) = match result {
+            Ok(diff) => {
+                log_cycle_result(&diff);
+                metrics.record_success(!diff.is_empty(), hooker.allow_list_len());
+                metrics.record_category_counts(hooker.category_counts());
+                consecutive_failures = 0;
+                if !is_ready {
+                    systemd::notify_ready();
+                    is_ready = true;
+                }
+                systemd::notify_watchdog();
+                (hooker.poll_interval(), true)
+            }
+            // Token doesn't fix itself, fail at startup instead of retrying forever
+            Err(err)
+                if !is_ready
+                    && matches!(
+                        err.downcast_ref::<FetchError>(),
+                        Some(FetchError::Unauthorized)
+                    ) =>
+            {
+                return Err(err);
+            }
+            Err(err) => {
+                log::error!("Update cycle failed. {:#}", err);
+                metrics.record_failure();
+                consecutive_failures += 1;
+                // Supervisor restarting the process is more likely to recover than looping on
+                if let Some(max_consecutive_failures) = hooker.config.max_consecutive_failures {
+                    if u64::from(consecutive_failures) >= max_consecutive_failures {
+                        log::error!(
+                            "Update cycle failed [{}] times in a row, exiting",
+                            consecutive_failures
+                        );
+                        return Err(err);
+                    }
+                }
+                let max_backoff_secs = hooker.config.max_backoff_secs();
+                let backoff = backoff_duration(repeat, max_backoff_secs, consecutive_failures);
+                // Retrying before rate limit resets only burns requests of the next window,
+                // backoff keeps skewed clock from retrying back to back
+                if let Some(FetchError::RateLimited {
+                    retry_after: Some(retry_after),
+                }) = err.downcast_ref::<FetchError>()
+                {
+                    let retry_after = (*retry_after).max(backoff);
+                    log::warn!(
+                        "Waiting for GitHub API rate limit reset at [{}]",
+                        humantime::format_rfc3339_seconds(
+                            std::time::SystemTime::now() + retry_after
+                        )
+                    );
+                    (retry_after, true)
                 } else {
-                    log::info!("Allow list is UNCHANGED");
+                    log::warn!(
+                        "Backing off for {:.1} seconds after {} consecutive failures",
+                        backoff.as_secs_f64(),
+                        consecutive_failures
+                    );
+                    (backoff, false)
                 }
             }
-            Err(err) => log::error!("Update cycle failed. {:#}", err),
         };
-        std::thread::sleep(std::time::Duration::from_secs(config.repeat));
+        let sleep_duration = sleep_duration + random_delay(hooker.config.jitter_secs.unwrap_or(0));
+        if is_on_schedule {
+            // Grace of one more repeat, single failed cycle is not a reason to restart
+            metrics.record_next_cycle(sleep_duration + std::time::Duration::from_secs(repeat));
+        }
+        next_fetch_at = clock.now() + sleep_duration;
+        sleep_until_next_wake(
+            &*clock,
+            &hooker,
+            next_fetch_at,
+            &[&shutdown_requested, &reload_requested],
+        );
     }
 
+    run_pending_hook(&mut hooker);
+
+    log::info!("Shutdown requested, exiting");
+
     Ok(())
 }
 
-fn try_fetch(authorization_header_value: &str) -> Result<HashSet<IpNetwork>, anyhow::Error> {
-    let client = reqwest::blocking::Client::new();
+/// Takes lock file of `config` before allow files, state file and their directories are touched,
+/// so second instance fails without creating or reading any of them. Directory of lock file is
+/// created with `create_parent_dirs`, it's next to allow file by default
+fn acquire_instance_lock(config: &Config) -> Result<lock::InstanceLock, anyhow::Error> {
+    let lock_file = config.lock_file();
+
+    if config.create_parent_dirs {
+        if let Some(directory) = lock_file
+            .parent()
+            .filter(|directory| !directory.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(directory).with_context(|| {
+                anyhow!(
+                    "Failed to create directory of lock file [{}]",
+                    lock_file.display()
+                )
+            })?;
+        }
+    }
 
-    let response = client
-        .request(reqwest::Method::GET, GITHUB_API_META_URL)
-        .header(reqwest::header::ACCEPT, ACCEPT_HEADER_VALUE)
-        .header(reqwest::header::AUTHORIZATION, authorization_header_value)
-        .header(reqwest::header::USER_AGENT, "reqwest")
-        .send()
-        .with_context(|| anyhow!("Failed to fetch GitHub meta information"))?;
+    lock::InstanceLock::acquire(&lock_file)
+}
 
-    if !response.status().is_success() {
-        return Err(anyhow!(
-            "GitHub API responded with code {}, text: {}",
-            response.status().as_u16(),
-            response.text().unwrap_or_default()
-        ));
+/// Lock file and PID file of reloaded configuration, `None` where path didn't change
+struct ReloadedLocks {
+    instance_lock: Option<lock::InstanceLock>,
+    pid_file:      Option<Option<lock::PidFile>>,
+}
+
+impl ReloadedLocks {
+    /// Takes lock file and PID file of `reloaded` configuration if their paths differ from
+    /// `current` ones, before its allow files are loaded. Nothing is taken if any of them can't be
+    fn take(current: &Config, reloaded: &Config) -> Result<Self, anyhow::Error> {
+        let instance_lock = if reloaded.lock_file() == current.lock_file() {
+            None
+        } else {
+            Some(acquire_instance_lock(reloaded)?)
+        };
+        let pid_file = if reloaded.pid_file == current.pid_file {
+            None
+        } else {
+            Some(
+                reloaded
+                    .pid_file
+                    .as_ref()
+                    .map(|pid_file| lock::PidFile::create(std::path::Path::new(pid_file)))
+                    .transpose()?,
+            )
+        };
+
+        Ok(Self {
+            instance_lock,
+            pid_file,
+        })
+    }
+
+    /// Puts taken locks in place of current ones, so new allow file is protected from second
+    /// instance too
+    fn replace(
+        self,
+        instance_lock: &mut Option<lock::InstanceLock>,
+        pid_file: &mut Option<lock::PidFile>,
+    ) {
+        if let Some(reloaded_lock) = self.instance_lock {
+            *instance_lock = Some(reloaded_lock);
+        }
+        if let Some(reloaded_pid_file) = self.pid_file {
+            *pid_file = reloaded_pid_file;
+        }
+    }
+}
+
+/// Metrics and health check servers keep listening where they were started, new addresses take
+/// effect only after restart
+fn warn_about_unreloadable_listeners(current: &Config, reloaded: &Config) {
+    for (key, current_listen, reloaded_listen) in [
+        (
+            "metrics_listen",
+            &current.metrics_listen,
+            &reloaded.metrics_listen,
+        ),
+        (
+            "health_listen",
+            &current.health_listen,
+            &reloaded.health_listen,
+        ),
+    ] {
+        if current_listen != reloaded_listen {
+            log::warn!(
+                "{} changed from [{}] to [{}], restart to apply it",
+                key,
+                current_listen.as_deref().unwrap_or("none"),
+                reloaded_listen.as_deref().unwrap_or("none")
+            );
+        }
     }
+}
 
-    let meta_info: MetaInfo = response
-        .json()
-        .with_context(|| anyhow!("Failed to deserialize GitHub meta information"))?;
+/// Runs postponed `after_update_hook` before `hooker` is dropped, allow files are already written
+/// and changes would never be announced otherwise
+fn run_pending_hook(hooker: &mut Hooker) {
+    if let Err(err) = hooker.run_pending_hook() {
+        log::error!("{:#}", err);
+    }
+}
 
-    Ok(HashSet::from_iter(meta_info.hooks.into_iter()))
+/// Sleeps until `next_fetch_at` or until postponed hook of `hooker` becomes due, whichever comes
+/// first, or until any of `flags` is raised by a signal handler
+fn sleep_until_next_wake(
+    clock: &dyn Clock,
+    hooker: &Hooker,
+    next_fetch_at: std::time::Instant,
+    flags: &[&AtomicBool],
+) {
+    let fetch_in = next_fetch_at.saturating_duration_since(clock.now());
+    let sleep_duration = hooker
+        .hook_due_in()
+        .map_or(fetch_in, |due_in| due_in.min(fetch_in));
+
+    sleep_until_signal(clock, sleep_duration, flags);
 }
 
-fn update_cycle(
-    authorization_header_value: &str,
-    allow_list: &mut AllowList,
-    after_update_hook: &str,
-) -> Result<bool, anyhow::Error> {
-    let hook_server_ips = try_fetch(authorization_header_value)
-        .with_context(|| anyhow!("Failed to get hook server ip addresses"))?;
+/// Sleeps for `duration` or until any of `flags` is raised by a signal handler
+fn sleep_until_signal(clock: &dyn Clock, duration: std::time::Duration, flags: &[&AtomicBool]) {
+    let deadline = clock.now() + duration;
+    let is_init = std::process::id() == 1;
 
-    if allow_list.update(hook_server_ips)? {
-        execute_after_update_hook(after_update_hook)
-            .with_context(|| anyhow!("Failed to execute after update hook"))?;
-        Ok(true)
-    } else {
-        Ok(false)
+    loop {
+        if is_init {
+            reap_orphans();
+        }
+
+        if flags.iter().any(|flag| flag.load(Ordering::Relaxed)) {
+            return;
+        }
+
+        let now = clock.now();
+        if now >= deadline {
+            return;
+        }
+
+        clock.sleep(SIGNAL_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Reaps exited processes reparented to this one. Running as PID 1, e.g. as container entrypoint
+/// without init, it inherits everything hooks left running in background. Hooks themselves are
+/// waited for synchronously, so nothing else is waiting for children at this point
+fn reap_orphans() {
+    loop {
+        // SAFETY: null status pointer is allowed, waitpid doesn't write through it then
+        let pid = unsafe { libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) };
+        if pid <= 0 {
+            return;
+        }
+        log::debug!("Reaped orphaned process [{}]", pid);
+    }
+}
+
+/// Doubles `repeat` for every consecutive failure up to `max_backoff_secs` and applies random
+/// jitter, so instances restarted together don't retry in lockstep
+fn backoff_duration(
+    repeat: u64,
+    max_backoff_secs: u64,
+    consecutive_failures: u32,
+) -> std::time::Duration {
+    let backoff_secs = repeat
+        .saturating_mul(2u64.saturating_pow(consecutive_failures))
+        .min(max_backoff_secs.max(repeat));
+
+    let jitter = rand::thread_rng().gen_range(-BACKOFF_JITTER..=BACKOFF_JITTER);
+
+    std::time::Duration::from_secs_f64(backoff_secs as f64 * (1.0 + jitter))
+}
+
+/// Random delay in `[0, max_secs]` added to sleeps, so instances started together spread their
+/// requests over time
+fn random_delay(max_secs: u64) -> std::time::Duration {
+    if max_secs == 0 {
+        return std::time::Duration::ZERO;
     }
+
+    std::time::Duration::from_secs_f64(rand::thread_rng().gen_range(0.0..=max_secs as f64))
 }
 
-fn execute_after_update_hook(after_update_hook: &str) -> Result<(), anyhow::Error> {
-    let exit_code = std::process::Command::new("bash")
-        .arg("-c")
-        .arg(after_update_hook)
-        .status()
-        .with_context(|| anyhow!("Failed to run after_update_hook"))?;
+/// Logs single grepable line per cycle, e.g. `cycle ok changed=true added=3 removed=1 total=566
+/// took=412ms`. `diff` is `None` for failed cycle
+fn log_cycle_summary(diff: Option<&AllowListDiff>, total: usize, took: std::time::Duration) {
+    match diff {
+        Some(diff) => log::info!(
+            "cycle ok changed={} added={} removed={} total={} took={}ms",
+            !diff.is_empty(),
+            diff.added.len(),
+            diff.removed.len(),
+            total,
+            took.as_millis()
+        ),
+        None => log::info!("cycle failed took={}ms", took.as_millis()),
+    }
+}
 
-    match exit_code.code() {
-        Some(0) => Ok(()),
-        Some(code) => Err(anyhow!("after_update_hook exited with non zero code")),
-        None => Err(anyhow!("Failed to get after_update_hook exit code")),
+fn log_cycle_result(diff: &AllowListDiff) {
+    log::info!("Update cycle completed");
+    if !diff.is_empty() {
+        log::info!(
+            target: module_path!(),
+            added = diff.added.len(),
+            removed = diff.removed.len();
+            "Allow list is CHANGED, {} added, {} removed",
+            diff.added.len(),
+            diff.removed.len()
+        );
+    } else {
+        log::info!("Allow list is UNCHANGED");
     }
 }
@@ -0,0 +1,925 @@
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use anyhow::{
+    anyhow,
+    Context,
+};
+use ipnetwork::IpNetwork;
+use serde::Deserialize;
+
+use crate::{
+    allow_list::normalized_cidr,
+    CidrSet,
+    Config,
+};
+
+pub const GITHUB_API_BASE_URL: &str = "https://api.github.com";
+const ACCEPT_HEADER_VALUE: &str = "application/vnd.github+json";
+/// GitHub API request timeout when `request_timeout_secs` is not configured
+pub(crate) const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+/// GitHub asks API clients to identify themselves with `User-Agent`
+pub(crate) const DEFAULT_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+const PEM_CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+/// Wait after `429 Too Many Requests` without any rate limit headers, as GitHub docs suggest. It
+/// also replaces zero wait, e.g. of rate limit reset that is in the past by local clock
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// Reason GitHub meta information couldn't be fetched
+#[derive(Debug)]
+#[derive(thiserror::Error)]
+pub enum FetchError {
+    /// Request didn't get response, `proxy` is configured proxy with credentials stripped
+    #[error("Failed to fetch GitHub meta information{}", through_proxy(.proxy))]
+    Network {
+        proxy:  Option<String>,
+        #[source]
+        source: reqwest::Error,
+    },
+    /// GitHub API refused request because rate limit is exceeded. `retry_after` is how long to
+    /// wait before next request, `None` if GitHub didn't say when rate limit resets
+    #[error("GitHub API rate limit exceeded{}", retry_in(.retry_after))]
+    RateLimited { retry_after: Option<Duration> },
+    /// GitHub API responded `401 Unauthorized`, configured token is wrong. Unlike exceeded rate
+    /// limit, retrying doesn't help
+    #[error("GitHub rejected the token (401) — check for whitespace or quoting in config.token")]
+    Unauthorized,
+    /// Any other unsuccessful response, with its status code and body
+    #[error("GitHub API responded with code {0}, text: {1}")]
+    BadStatus(u16, String),
+    #[error("Failed to deserialize GitHub meta information")]
+    Deserialize(#[source] reqwest::Error),
+    /// Response doesn't have configured category at all, which is not the same as empty one
+    #[error("GitHub meta information has no [{0}] category")]
+    MissingCategory(String),
+    /// Category of `category_urls` couldn't be fetched
+    #[error("Failed to fetch category [{category}] from [{url}]")]
+    Category {
+        category: String,
+        url:      String,
+        #[source]
+        source:   Box<FetchError>,
+    },
+}
+
+impl FetchError {
+    /// Builds `RateLimited` from `Retry-After` or `X-RateLimit-Reset` headers of `403 Forbidden`
+    /// and `429 Too Many Requests` responses, `None` if response is not about rate limit
+    fn rate_limited(response: &reqwest::blocking::Response) -> Option<Self> {
+        let status = response.status();
+        if status != reqwest::StatusCode::FORBIDDEN
+            && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+        {
+            return None;
+        }
+
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+
+        let retry_after = if let Some(retry_after_secs) = header("retry-after") {
+            Some(Duration::from_secs(retry_after_secs))
+        } else if header("x-ratelimit-remaining") == Some(0) {
+            // Reset is Unix timestamp, GitHub sends it along with remaining requests count
+            let now_secs = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default();
+            header("x-ratelimit-reset")
+                .map(|reset| Duration::from_secs(reset.saturating_sub(now_secs)))
+        } else if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            Some(DEFAULT_RATE_LIMIT_WAIT)
+        } else {
+            return None;
+        };
+        // `Retry-After: 0` or reset that already passed by local clock would retry right away
+        // while GitHub still counts the old window
+        let retry_after = retry_after.map(|retry_after| {
+            if retry_after.is_zero() {
+                DEFAULT_RATE_LIMIT_WAIT
+            } else {
+                retry_after
+            }
+        });
+
+        Some(Self::RateLimited { retry_after })
+    }
+
+    /// Returns `true` if another token may succeed where this request failed
+    pub fn is_token_specific(&self) -> bool {
+        matches!(self, Self::Unauthorized | Self::RateLimited { .. })
+    }
+
+    /// Turns unsuccessful `response` into error, successful one is returned as is
+    fn check_status(
+        response: reqwest::blocking::Response,
+    ) -> Result<reqwest::blocking::Response, Self> {
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(Self::Unauthorized);
+        }
+
+        if let Some(rate_limited) = Self::rate_limited(&response) {
+            return Err(rate_limited);
+        }
+
+        if !response.status().is_success() {
+            return Err(Self::BadStatus(
+                response.status().as_u16(),
+                response.text().unwrap_or_default(),
+            ));
+        }
+
+        Ok(response)
+    }
+}
+
+fn through_proxy(proxy: &Option<String>) -> String {
+    match proxy {
+        Some(proxy) => format!(" through proxy [{}]", proxy),
+        None => String::new(),
+    }
+}
+
+fn retry_in(retry_after: &Option<Duration>) -> String {
+    match retry_after {
+        Some(retry_after) => format!(
+            ", retry in {} at {}",
+            humantime::format_duration(*retry_after),
+            humantime::format_rfc3339_seconds(SystemTime::now() + *retry_after)
+        ),
+        None => String::new(),
+    }
+}
+
+#[derive(Default)]
+#[derive(Deserialize)]
+struct MetaInfo {
+    pub hooks:    Option<Vec<IpNetwork>>,
+    pub web:      Option<Vec<IpNetwork>>,
+    pub api:      Option<Vec<IpNetwork>>,
+    pub git:      Option<Vec<IpNetwork>>,
+    pub actions:  Option<Vec<IpNetwork>>,
+    pub packages: Option<Vec<IpNetwork>>,
+    pub pages:    Option<Vec<IpNetwork>>,
+    pub importer: Option<Vec<IpNetwork>>,
+}
+
+impl MetaInfo {
+    /// Returns ip addresses of specified category, `None` if GitHub didn't return it
+    pub fn category(&self, name: &str) -> Option<&Vec<IpNetwork>> {
+        match name {
+            "hooks" => self.hooks.as_ref(),
+            "web" => self.web.as_ref(),
+            "api" => self.api.as_ref(),
+            "git" => self.git.as_ref(),
+            "actions" => self.actions.as_ref(),
+            "packages" => self.packages.as_ref(),
+            "pages" => self.pages.as_ref(),
+            "importer" => self.importer.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Replaces ip addresses of specified category, unknown categories are ignored
+    pub fn set_category(&mut self, name: &str, ips: Option<Vec<IpNetwork>>) {
+        match name {
+            "hooks" => self.hooks = ips,
+            "web" => self.web = ips,
+            "api" => self.api = ips,
+            "git" => self.git = ips,
+            "actions" => self.actions = ips,
+            "packages" => self.packages = ips,
+            "pages" => self.pages = ips,
+            "importer" => self.importer = ips,
+            _ => {}
+        }
+    }
+
+    /// Fails on the first of `categories` GitHub didn't return if `require_categories` is set,
+    /// otherwise warns about every one of them
+    fn check_categories<'a>(
+        &self,
+        categories: impl IntoIterator<Item = &'a str>,
+        require_categories: bool,
+    ) -> Result<(), FetchError> {
+        for category in categories {
+            if self.category(category).is_some() {
+                continue;
+            }
+
+            if require_categories {
+                return Err(FetchError::MissingCategory(category.to_string()));
+            }
+            log::warn!(
+                "GitHub meta information has no [{}] category, it's skipped",
+                category
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns union of ip addresses of specified categories
+    pub fn ips(&self, categories: &[String]) -> CidrSet {
+        let mut ips = CidrSet::default();
+
+        for category in categories {
+            if let Some(category_ips) = self.category(category) {
+                ips.extend(category_ips.iter().copied().map(normalized_cidr));
+            }
+        }
+
+        ips
+    }
+
+    /// Number of ip addresses of every specified category, `0` for missing ones
+    pub fn category_counts(&self, categories: &[String]) -> Vec<(String, usize)> {
+        categories
+            .iter()
+            .map(|category| {
+                (
+                    category.clone(),
+                    self.category(category).map_or(0, Vec::len),
+                )
+            })
+            .collect()
+    }
+
+    /// Maps every ip address of specified categories to categories it belongs to
+    pub fn categories_by_cidr(&self, categories: &[String]) -> HashMap<IpNetwork, Vec<String>> {
+        let mut categories_by_cidr: HashMap<IpNetwork, Vec<String>> = HashMap::new();
+
+        for category in categories {
+            for cidr in self.category(category).into_iter().flatten() {
+                categories_by_cidr
+                    .entry(normalized_cidr(*cidr))
+                    .or_default()
+                    .push(category.clone());
+            }
+        }
+
+        categories_by_cidr
+    }
+}
+
+/// Response of `category_urls` endpoint
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CategoryDocument {
+    Cidrs(Vec<IpNetwork>),
+    MetaInfo(MetaInfo),
+}
+
+/// Fetches union of ip addresses of `categories` from public GitHub meta API in one go
+///
+/// ```no_run
+/// let client = reqwest::blocking::Client::new();
+/// let categories = vec![String::from("hooks")];
+///
+/// let ips = github_nginx_hooker::fetch_meta(&client, "ghp_token", &categories)?;
+///
+/// println!("GitHub sends hooks from {} CIDRs", ips.len());
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn fetch_meta(
+    client: &reqwest::blocking::Client,
+    token: &str,
+    categories: &[String],
+) -> Result<CidrSet, FetchError> {
+    MetaClient {
+        client: client.clone(),
+        meta_url: format!("{}/meta", GITHUB_API_BASE_URL),
+        category_urls: BTreeMap::new(),
+        optional_categories: Vec::new(),
+        require_categories: true,
+        authorization_header_values: vec![format!("token {}", token)],
+        user_agent: DEFAULT_USER_AGENT.to_string(),
+        etag: None,
+        meta_info: None,
+        max_age: None,
+        proxy: None,
+    }
+    .try_fetch(categories)
+}
+
+/// GitHub meta API client that remembers last response and revalidates it with `ETag`. It's
+/// meant to live as long as configuration does, so keep-alive connections of its HTTP client
+/// are reused by following requests
+pub struct MetaClient {
+    client: reqwest::blocking::Client,
+    meta_url: String,
+    /// Categories fetched from their own URLs instead of `meta_url`
+    category_urls: BTreeMap<String, String>,
+    /// Categories of `category_urls` that are skipped if they can't be fetched
+    optional_categories: Vec<String>,
+    /// Fail if response lacks any requested category instead of warning
+    require_categories: bool,
+    /// One per token in order they're tried, empty for unauthenticated requests
+    authorization_header_values: Vec<String>,
+    user_agent: String,
+    /// `ETag` of last successful response
+    etag: Option<String>,
+    /// Last successfully fetched meta information
+    meta_info: Option<MetaInfo>,
+    /// How long GitHub said last response stays fresh
+    max_age: Option<Duration>,
+    /// Configured `http_proxy` with credentials stripped, used in error messages
+    proxy: Option<String>,
+}
+
+impl MetaClient {
+    pub fn from_config(config: &Config) -> Result<Self, anyhow::Error> {
+        let request_timeout = std::time::Duration::from_secs(
+            config
+                .request_timeout_secs
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS),
+        );
+
+        let authorization_header_values: Vec<String> = config
+            .token
+            .iter()
+            .chain(&config.tokens)
+            .map(|token| format!("{} {}", config.auth_scheme.as_str(), token))
+            .collect();
+        match authorization_header_values.len() {
+            0 => log::info!(
+                "GitHub API token is not configured, requests are unauthenticated with lower rate \
+                 limit"
+            ),
+            1 => log::info!(
+                "GitHub API requests are authenticated with [{}] token",
+                config.auth_scheme.as_str()
+            ),
+            count => log::info!(
+                "GitHub API requests are authenticated with [{}] tokens, [{}] of them are tried \
+                 in order",
+                config.auth_scheme.as_str(),
+                count
+            ),
+        }
+
+        let mut client_builder = reqwest::blocking::Client::builder()
+            .timeout(request_timeout)
+            .tcp_keepalive(config.tcp_keepalive_secs.map(Duration::from_secs));
+        if config.http2_prior_knowledge {
+            client_builder = client_builder.http2_prior_knowledge();
+        }
+
+        // Without explicit proxy reqwest picks one from environment variables per URL, see
+        // `env_proxy`. Credentials embedded in proxy URL are used for proxy authentication in
+        // both cases
+        let proxy = match &config.http_proxy {
+            Some(http_proxy) => {
+                client_builder = client_builder.proxy(reqwest::Proxy::all(http_proxy.as_str())?);
+                Some(redact_url_credentials(http_proxy))
+            }
+            None => None,
+        };
+
+        let ca_certificates = match &config.ca_bundle {
+            Some(ca_bundle) => read_ca_bundle(ca_bundle)?,
+            None => Vec::new(),
+        };
+
+        if config.accept_invalid_certs {
+            log::warn!(
+                "danger_accept_invalid_certs is enabled, GitHub API certificate is NOT verified \
+                 and anyone in between can forge allow list"
+            );
+        }
+
+        // Preconfigured TLS connector ignores TLS settings of client builder
+        if let (Some(client_cert), Some(client_key)) = (&config.client_cert, &config.client_key) {
+            client_builder = with_client_identity(
+                client_builder,
+                client_cert,
+                client_key,
+                &ca_certificates,
+                config.accept_invalid_certs,
+            )?;
+        } else {
+            if config.ca_bundle.is_some() {
+                client_builder = client_builder.tls_built_in_root_certs(false);
+            }
+            for ca_certificate in &ca_certificates {
+                client_builder = client_builder
+                    .add_root_certificate(reqwest::Certificate::from_pem(ca_certificate)?);
+            }
+            client_builder =
+                client_builder.danger_accept_invalid_certs(config.accept_invalid_certs);
+        }
+
+        Ok(Self {
+            client: client_builder.build()?,
+            meta_url: config.meta_url(),
+            category_urls: config.category_urls.clone(),
+            optional_categories: config.optional_categories.clone(),
+            require_categories: config.require_categories,
+            authorization_header_values,
+            user_agent: config
+                .user_agent
+                .clone()
+                .unwrap_or_else(|| DEFAULT_USER_AGENT.to_string()),
+            etag: None,
+            meta_info: None,
+            max_age: None,
+            proxy,
+        })
+    }
+
+    /// Takes cached meta information and its `ETag` over from client of previous configuration,
+    /// so the first request after reload can still be answered `304 Not Modified`. Cache is
+    /// dropped if it was fetched from other URLs
+    pub(crate) fn keep_cache_of(&mut self, previous: MetaClient) {
+        if previous.meta_url != self.meta_url || previous.category_urls != self.category_urls {
+            return;
+        }
+
+        self.etag = previous.etag;
+        self.meta_info = previous.meta_info;
+        self.max_age = previous.max_age;
+    }
+
+    /// Proxy requests of `url` go through with credentials stripped, `None` if they go directly
+    fn proxy_for(&self, url: &str) -> Option<String> {
+        self.proxy.clone().or_else(|| env_proxy(url))
+    }
+
+    /// HTTP client configured with `request_timeout_secs` and `http_proxy`
+    pub(crate) fn http_client(&self) -> &reqwest::blocking::Client {
+        &self.client
+    }
+
+    /// How long last response stays fresh according to its `Cache-Control: max-age` or `Expires`
+    /// header, `None` if GitHub didn't say
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age
+    }
+
+    /// Number of ip addresses of every category in last successful response, empty if nothing
+    /// was fetched yet
+    pub fn category_counts(&self, categories: &[String]) -> Vec<(String, usize)> {
+        self.meta_info
+            .as_ref()
+            .map(|meta_info| meta_info.category_counts(categories))
+            .unwrap_or_default()
+    }
+
+    /// Categories of ip addresses from last successful response, empty if nothing was fetched yet
+    pub fn categories_by_cidr(&self, categories: &[String]) -> HashMap<IpNetwork, Vec<String>> {
+        self.meta_info
+            .as_ref()
+            .map(|meta_info| meta_info.categories_by_cidr(categories))
+            .unwrap_or_default()
+    }
+
+    /// Fetches GitHub meta information and returns union of ip addresses of `categories`.
+    /// Categories of `category_urls` are fetched concurrently with meta information, failed
+    /// optional ones are skipped with warning
+    pub fn try_fetch(&mut self, categories: &[String]) -> Result<CidrSet, FetchError> {
+        let (url_categories, meta_url_categories): (Vec<String>, Vec<String>) = categories
+            .iter()
+            .cloned()
+            .partition(|category| self.category_urls.contains_key(category));
+        if url_categories.is_empty() {
+            return self.try_fetch_meta_url(categories);
+        }
+
+        // URLs are copied as requests are sent while `self` fetches meta information
+        let category_requests: Vec<CategoryRequests> = url_categories
+            .iter()
+            .map(|category| {
+                let url = &self.category_urls[category];
+                CategoryRequests {
+                    category,
+                    url: redact_url_credentials(url),
+                    proxy: self.proxy_for(url),
+                    requests: self.category_requests(url),
+                }
+            })
+            .collect();
+        let require_categories = self.require_categories;
+
+        let (meta_url_result, category_results) = std::thread::scope(|scope| {
+            let handles: Vec<_> = category_requests
+                .into_iter()
+                .map(
+                    |CategoryRequests {
+                         category,
+                         url,
+                         proxy,
+                         requests,
+                     }| {
+                        scope.spawn(move || {
+                            fetch_category(requests, category, require_categories, proxy).map_err(
+                                |source| FetchError::Category {
+                                    category: category.clone(),
+                                    url,
+                                    source: Box::new(source),
+                                },
+                            )
+                        })
+                    },
+                )
+                .collect();
+
+            let meta_url_result = if meta_url_categories.is_empty() {
+                Ok(CidrSet::default())
+            } else {
+                self.try_fetch_meta_url(&meta_url_categories)
+            };
+
+            let category_results: Vec<Result<Vec<IpNetwork>, FetchError>> = handles
+                .into_iter()
+                .map(|handle| handle.join().expect("category fetch doesn't panic"))
+                .collect();
+
+            (meta_url_result, category_results)
+        });
+        meta_url_result?;
+
+        let meta_info = self.meta_info.get_or_insert_with(MetaInfo::default);
+        for (category, category_result) in url_categories.iter().zip(category_results) {
+            match category_result {
+                Ok(ips) => meta_info.set_category(category, Some(ips)),
+                Err(err) if self.optional_categories.contains(category) => {
+                    log::warn!("Optional category [{}] is skipped. {:#}", category, err);
+                    meta_info.set_category(category, None);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(meta_info.ips(categories))
+    }
+
+    /// Fetches `categories` from `meta_url`. Previously fetched meta information is reused if
+    /// GitHub responds `304 Not Modified`. Tokens are tried in order, the next one is used if
+    /// GitHub rejects or rate limits previous
+    fn try_fetch_meta_url(&mut self, categories: &[String]) -> Result<CidrSet, FetchError> {
+        let authorization_header_values = self.authorization_header_values.clone();
+        if authorization_header_values.is_empty() {
+            return self.fetch_with(categories, None);
+        }
+
+        let last_index = authorization_header_values.len() - 1;
+        for (index, authorization_header_value) in authorization_header_values.iter().enumerate() {
+            match self.fetch_with(categories, Some(authorization_header_value)) {
+                Ok(ips) => {
+                    if last_index > 0 {
+                        log::info!(
+                            "GitHub meta information is fetched with token #{}",
+                            index + 1
+                        );
+                    }
+                    return Ok(ips);
+                }
+                Err(err) if index < last_index && err.is_token_specific() => {
+                    log::warn!(
+                        "GitHub API token #{} can't be used, trying the next one. {}",
+                        index + 1,
+                        err
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        unreachable!("the last token either succeeds or returns its error")
+    }
+
+    /// Requests of category `url`, one per token in order they're tried. Tokens are only sent to
+    /// the origin of `meta_url`, third party mirrors are requested unauthenticated
+    fn category_requests(&self, url: &str) -> Vec<reqwest::blocking::RequestBuilder> {
+        let origin = |url: &str| url::Url::parse(url).map(|url| url.origin()).ok();
+        let is_meta_url_origin = matches!(
+            (origin(url), origin(&self.meta_url)),
+            (Some(origin), Some(meta_url_origin)) if origin.is_tuple() && origin == meta_url_origin
+        );
+
+        if !is_meta_url_origin || self.authorization_header_values.is_empty() {
+            return vec![self.request(url, None::<&str>)];
+        }
+
+        self.authorization_header_values
+            .iter()
+            .map(|authorization_header_value| self.request(url, Some(authorization_header_value)))
+            .collect()
+    }
+
+    /// Single request of `try_fetch`, unauthenticated if `authorization_header_value` is `None`
+    fn fetch_with(
+        &mut self,
+        categories: &[String],
+        authorization_header_value: Option<&str>,
+    ) -> Result<CidrSet, FetchError> {
+        let mut request = self.request(&self.meta_url, authorization_header_value);
+
+        if let (Some(etag), Some(_)) = (&self.etag, &self.meta_info) {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+
+        let response = request.send().map_err(|source| FetchError::Network {
+            proxy: self.proxy_for(&self.meta_url),
+            source,
+        })?;
+
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            if let Some(meta_info) = &self.meta_info {
+                log::debug!("GitHub meta information is not modified");
+                self.max_age = max_age(response.headers());
+                return Ok(meta_info.ips(categories));
+            }
+        }
+
+        let response = FetchError::check_status(response)?;
+
+        let etag = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+
+        let headers = response.headers().clone();
+
+        let meta_info: MetaInfo = response.json().map_err(FetchError::Deserialize)?;
+        // Malformed response is not stored, so it isn't revalidated with its ETag either
+        meta_info.check_categories(
+            categories.iter().map(String::as_str),
+            self.require_categories,
+        )?;
+
+        let ips = meta_info.ips(categories);
+
+        self.etag = etag;
+        self.meta_info = Some(meta_info);
+        self.max_age = max_age(&headers);
+
+        Ok(ips)
+    }
+
+    /// GET request of `url` with GitHub API headers, unauthenticated if
+    /// `authorization_header_value` is `None`
+    fn request(
+        &self,
+        url: &str,
+        authorization_header_value: Option<impl AsRef<str>>,
+    ) -> reqwest::blocking::RequestBuilder {
+        let request = self
+            .client
+            .request(reqwest::Method::GET, url)
+            .header(reqwest::header::ACCEPT, ACCEPT_HEADER_VALUE)
+            .header(reqwest::header::USER_AGENT, &self.user_agent);
+
+        match authorization_header_value {
+            Some(authorization_header_value) => request.header(
+                reqwest::header::AUTHORIZATION,
+                authorization_header_value.as_ref(),
+            ),
+            None => request,
+        }
+    }
+}
+
+/// Requests of single `category_urls` category, sent from its own thread
+struct CategoryRequests<'a> {
+    category: &'a String,
+    /// URL with credentials stripped, used in error messages
+    url:      String,
+    proxy:    Option<String>,
+    /// One per token in order they're tried
+    requests: Vec<reqwest::blocking::RequestBuilder>,
+}
+
+/// Sends `requests` to `category_urls` endpoint in order until one isn't rejected for its token
+/// and returns ip addresses of `category` it responded with
+fn fetch_category(
+    requests: Vec<reqwest::blocking::RequestBuilder>,
+    category: &str,
+    require_categories: bool,
+    proxy: Option<String>,
+) -> Result<Vec<IpNetwork>, FetchError> {
+    let last_index = requests.len() - 1;
+    for (index, request) in requests.into_iter().enumerate() {
+        let result = request
+            .send()
+            .map_err(|source| FetchError::Network {
+                proxy: proxy.clone(),
+                source,
+            })
+            .and_then(FetchError::check_status);
+
+        match result {
+            Ok(response) => return read_category(response, category, require_categories),
+            Err(err) if index < last_index && err.is_token_specific() => {
+                log::warn!(
+                    "GitHub API token #{} can't be used for category [{}], trying the next one. {}",
+                    index + 1,
+                    category,
+                    err
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    unreachable!("the last request either succeeds or returns its error")
+}
+
+/// Reads ip addresses of `category` from successful `category_urls` endpoint response
+fn read_category(
+    response: reqwest::blocking::Response,
+    category: &str,
+    require_categories: bool,
+) -> Result<Vec<IpNetwork>, FetchError> {
+    let ips = match response.json().map_err(FetchError::Deserialize)? {
+        CategoryDocument::Cidrs(ips) => ips,
+        CategoryDocument::MetaInfo(meta_info) => {
+            meta_info.check_categories([category], require_categories)?;
+            meta_info.category(category).cloned().unwrap_or_default()
+        }
+    };
+
+    log::debug!("Fetched [{}] CIDRs of category [{}]", ips.len(), category);
+
+    Ok(ips)
+}
+
+/// Reads PEM certificates of `ca_bundle`, they're trusted instead of system root store
+fn read_ca_bundle(ca_bundle: &str) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+    let content = std::fs::read_to_string(ca_bundle)
+        .with_context(|| anyhow!("Failed to read CA bundle [{}]", ca_bundle))?;
+
+    let ca_certificates: Vec<Vec<u8>> = content
+        .split_inclusive(PEM_CERTIFICATE_END)
+        .filter(|block| block.contains(PEM_CERTIFICATE_BEGIN))
+        .map(|block| block.trim().as_bytes().to_vec())
+        .collect();
+
+    if ca_certificates.is_empty() {
+        return Err(anyhow!(
+            "CA bundle [{}] doesn't contain PEM certificates",
+            ca_bundle
+        ));
+    }
+
+    Ok(ca_certificates)
+}
+
+/// Presents `client_cert` with `client_key` to servers and proxies requiring TLS client
+/// authentication. Key not matching certificate is detected here rather than on first request
+#[cfg(feature = "client-cert")]
+fn with_client_identity(
+    client_builder: reqwest::blocking::ClientBuilder,
+    client_cert: &str,
+    client_key: &str,
+    ca_certificates: &[Vec<u8>],
+    danger_accept_invalid_certs: bool,
+) -> Result<reqwest::blocking::ClientBuilder, anyhow::Error> {
+    let cert = std::fs::read(client_cert)
+        .with_context(|| anyhow!("Failed to read client certificate [{}]", client_cert))?;
+    let key = std::fs::read(client_key)
+        .with_context(|| anyhow!("Failed to read client key [{}]", client_key))?;
+
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key).with_context(|| {
+        anyhow!(
+            "Failed to load client certificate [{}] with key [{}], key must be PKCS #8 PEM",
+            client_cert,
+            client_key
+        )
+    })?;
+    let mut tls_connector_builder = native_tls::TlsConnector::builder();
+    tls_connector_builder
+        .identity(identity)
+        .danger_accept_invalid_certs(danger_accept_invalid_certs);
+    if !ca_certificates.is_empty() {
+        tls_connector_builder.disable_built_in_roots(true);
+    }
+    for ca_certificate in ca_certificates {
+        tls_connector_builder
+            .add_root_certificate(native_tls::Certificate::from_pem(ca_certificate)?);
+    }
+
+    let tls_connector = tls_connector_builder.build().with_context(|| {
+        anyhow!(
+            "Client certificate [{}] doesn't match key [{}]",
+            client_cert,
+            client_key
+        )
+    })?;
+
+    Ok(client_builder.use_preconfigured_tls(tls_connector))
+}
+
+#[cfg(not(feature = "client-cert"))]
+fn with_client_identity(
+    _client_builder: reqwest::blocking::ClientBuilder,
+    _client_cert: &str,
+    _client_key: &str,
+    _ca_certificates: &[Vec<u8>],
+    _danger_accept_invalid_certs: bool,
+) -> Result<reqwest::blocking::ClientBuilder, anyhow::Error> {
+    Err(anyhow!(
+        "client_cert requires building with `--features client-cert`"
+    ))
+}
+
+/// Freshness lifetime of response, `Cache-Control: max-age` takes precedence over `Expires`
+fn max_age(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let cache_control_max_age = headers
+        .get_all(reqwest::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|max_age| max_age.trim_matches('"').parse::<u64>().ok())
+        .map(Duration::from_secs);
+
+    cache_control_max_age.or_else(|| {
+        let expires = headers
+            .get(reqwest::header::EXPIRES)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())?;
+
+        expires.duration_since(SystemTime::now()).ok()
+    })
+}
+
+/// Proxy reqwest picks from environment for `url` with credentials stripped: `HTTPS_PROXY` for
+/// https URLs and `HTTP_PROXY` for http ones, unless host is listed in `NO_PROXY`. There is no
+/// fallback to `ALL_PROXY`, reqwest doesn't read it
+fn env_proxy(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let names = match url.scheme() {
+        "https" => ["HTTPS_PROXY", "https_proxy"],
+        // Any CGI client can set it with `Proxy` header, so reqwest ignores it there
+        "http" if std::env::var_os("REQUEST_METHOD").is_none() => ["HTTP_PROXY", "http_proxy"],
+        _ => return None,
+    };
+    let proxy = names
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|proxy| !proxy.trim().is_empty())?;
+
+    if is_no_proxy_host(url.host_str()?) {
+        return None;
+    }
+
+    Some(redact_url_credentials(&proxy))
+}
+
+/// Matches `host` against `NO_PROXY` entries the way reqwest does: IP addresses and networks
+/// match IP hosts, domains match themselves and their subdomains, `*` matches any domain
+fn is_no_proxy_host(host: &str) -> bool {
+    let no_proxy = std::env::var("NO_PROXY")
+        .or_else(|_| std::env::var("no_proxy"))
+        .unwrap_or_default();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host_ip = host.parse::<std::net::IpAddr>().ok();
+
+    no_proxy
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| match host_ip {
+            Some(host_ip) => entry
+                .parse::<IpNetwork>()
+                .is_ok_and(|network| network.contains(host_ip)),
+            None => {
+                let domain = entry.trim_start_matches('.');
+                entry == "*"
+                    || host == domain
+                    || host
+                        .strip_suffix(domain)
+                        .is_some_and(|subdomain| subdomain.ends_with('.'))
+            }
+        })
+}
+
+/// Strips username and password from `url`, so it can be logged
+pub(crate) fn redact_url_credentials(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut url) => {
+            // Fails only for URLs that can't have credentials at all
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            url.to_string()
+        }
+        Err(_) => String::from("<unparsable proxy URL>"),
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    io::{
+        BufRead,
+        BufReader,
+        Write,
+    },
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+    sync::{
+        atomic::{
+            AtomicI64,
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+/// Time to wait for scraper to send request before dropping connection
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Update cycle statistics shared between main loop and metrics server
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Number of finished update cycles, both successful and failed
+    cycles_total:          AtomicU64,
+    cycle_failures_total:  AtomicU64,
+    /// Unix timestamp of last allow list change, `0` if it didn't change yet
+    last_change_timestamp: AtomicI64,
+    /// Unix timestamp of last successful update cycle, `0` if none succeeded yet
+    last_success:          AtomicI64,
+    /// Unix timestamp health check passes until before the first successful update cycle, it
+    /// covers `startup_delay_secs`
+    startup_deadline:      AtomicI64,
+    /// Unix timestamp health check passes until after the first successful update cycle, it's
+    /// moved forward every time the next cycle is scheduled on time
+    next_cycle_deadline:   AtomicI64,
+    /// Unix timestamp daemon started at, `0` until startup delay is recorded
+    started_at:            AtomicI64,
+    allow_list_size:       AtomicU64,
+    /// Number of CIDRs GitHub returned for every configured category
+    category_cidrs:        Mutex<Vec<(String, usize)>>,
+}
+
+impl Metrics {
+    pub fn record_success(&self, is_changed: bool, allow_list_size: usize) {
+        let now = unix_timestamp();
+
+        self.cycles_total.fetch_add(1, Ordering::Relaxed);
+        self.allow_list_size
+            .store(allow_list_size as u64, Ordering::Relaxed);
+        self.last_success.store(now, Ordering::Relaxed);
+
+        if is_changed {
+            self.last_change_timestamp.store(now, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_category_counts(&self, category_counts: Vec<(String, usize)>) {
+        *self
+            .category_cidrs
+            .lock()
+            .expect("metrics lock is not poisoned") = category_counts;
+    }
+
+    /// Keeps health check passing for `startup_delay` while the first update cycle waits for it
+    pub fn record_startup_delay(&self, startup_delay: Duration) {
+        let now = unix_timestamp();
+
+        self.started_at.store(now, Ordering::Relaxed);
+        self.startup_deadline.store(
+            now + startup_delay.as_secs_f64().ceil() as i64,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Keeps health check passing for `due_in` while the main loop waits for the next cycle,
+    /// deadline never moves back
+    pub fn record_next_cycle(&self, due_in: Duration) {
+        self.next_cycle_deadline.fetch_max(
+            unix_timestamp() + due_in.as_secs_f64().ceil() as i64,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Returns `true` if the next update cycle is not overdue since the last successful one or
+    /// the first one is still delayed by `startup_delay_secs`
+    pub fn is_healthy(&self) -> bool {
+        if self.last_success.load(Ordering::Relaxed) == 0 {
+            return unix_timestamp() < self.startup_deadline.load(Ordering::Relaxed);
+        }
+
+        unix_timestamp() <= self.next_cycle_deadline.load(Ordering::Relaxed)
+    }
+
+    pub fn record_failure(&self) {
+        self.cycles_total.fetch_add(1, Ordering::Relaxed);
+        self.cycle_failures_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        for (name, metric_type, help, value) in [
+            (
+                "hooker_cycles_total",
+                "counter",
+                "Total number of update cycles",
+                self.cycles_total.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "hooker_cycle_failures_total",
+                "counter",
+                "Total number of failed update cycles",
+                self.cycle_failures_total.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "hooker_last_change_timestamp",
+                "gauge",
+                "Unix timestamp of last allow list change",
+                self.last_change_timestamp.load(Ordering::Relaxed),
+            ),
+            (
+                "hooker_allow_list_size",
+                "gauge",
+                "Number of CIDRs in allow list",
+                self.allow_list_size.load(Ordering::Relaxed) as i64,
+            ),
+        ] {
+            output.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} {metric_type}\n{name} {value}\n"
+            ));
+        }
+
+        output.push_str(
+            "# HELP hooker_cidrs Number of CIDRs GitHub returned for category\n# TYPE \
+             hooker_cidrs gauge\n",
+        );
+        for (category, count) in self
+            .category_cidrs
+            .lock()
+            .expect("metrics lock is not poisoned")
+            .iter()
+        {
+            output.push_str(&format!(
+                "hooker_cidrs{{category=\"{}\"}} {}\n",
+                category, count
+            ));
+        }
+
+        output
+    }
+
+    /// Renders last success and change timestamps, allow list size, failed cycles and uptime as
+    /// JSON object for scripts. Timestamps are `null` until the first success or change
+    pub fn render_stats(&self) -> String {
+        let timestamp = |timestamp: &AtomicI64| match timestamp.load(Ordering::Relaxed) {
+            0 => None,
+            timestamp => Some(timestamp),
+        };
+        let started_at = self.started_at.load(Ordering::Relaxed);
+        let uptime_secs = if started_at == 0 {
+            0
+        } else {
+            unix_timestamp().saturating_sub(started_at).max(0)
+        };
+
+        let mut output = serde_json::json!({
+            "last_success": timestamp(&self.last_success),
+            "last_change": timestamp(&self.last_change_timestamp),
+            "total": self.allow_list_size.load(Ordering::Relaxed),
+            "failures": self.cycle_failures_total.load(Ordering::Relaxed),
+            "uptime_secs": uptime_secs,
+        })
+        .to_string();
+        output.push('\n');
+
+        output
+    }
+}
+
+/// Binds `listen_address` and serves `GET /metrics` and `GET /stats` from a background thread
+pub fn spawn_server(listen_address: &str, metrics: Arc<Metrics>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(listen_address)?;
+
+    log::info!(
+        "Serving metrics on http://{0}/metrics and http://{0}/stats",
+        listen_address
+    );
+
+    spawn_listener(listener, move |path| match path {
+        "/metrics" => Some(("200 OK", TEXT_CONTENT_TYPE, metrics.render())),
+        "/stats" => Some(("200 OK", JSON_CONTENT_TYPE, metrics.render_stats())),
+        _ => None,
+    });
+
+    Ok(())
+}
+
+/// Binds `listen_address` and serves `GET /health` from a background thread. It responds
+/// `200 OK` while the next update cycle is not overdue and `503` otherwise
+pub fn spawn_health_server(listen_address: &str, metrics: Arc<Metrics>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(listen_address)?;
+
+    log::info!("Serving health check on http://{}/health", listen_address);
+
+    spawn_listener(listener, move |path| match path {
+        "/health" if metrics.is_healthy() => {
+            Some(("200 OK", TEXT_CONTENT_TYPE, String::from("OK\n")))
+        }
+        "/health" => Some((
+            "503 Service Unavailable",
+            TEXT_CONTENT_TYPE,
+            String::from("No successful update cycle recently\n"),
+        )),
+        _ => None,
+    });
+
+    Ok(())
+}
+
+/// Serves connections one by one, `route` maps `GET` request path to response status, content
+/// type and body, `None` is responded with `404`
+fn spawn_listener<F>(listener: TcpListener, route: F)
+where
+    F: Fn(&str) -> Option<(&'static str, &'static str, String)> + Send + 'static,
+{
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle_connection(stream, &route));
+
+            if let Err(err) = result {
+                log::warn!("Failed to serve HTTP request. {:#}", err);
+            }
+        }
+    });
+}
+
+fn handle_connection<F>(mut stream: TcpStream, route: &F) -> std::io::Result<()>
+where
+    F: Fn(&str) -> Option<(&'static str, &'static str, String)>,
+{
+    stream.set_read_timeout(Some(REQUEST_READ_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Drain headers, closing socket with unread data makes clients see connection reset
+    let mut header_line = String::new();
+    while reader.read_line(&mut header_line)? > 2 {
+        header_line.clear();
+    }
+
+    let mut request_parts = request_line.split_whitespace();
+    let response = match (request_parts.next(), request_parts.next()) {
+        (Some("GET"), Some(path)) => route(path),
+        _ => None,
+    };
+    let (status, content_type, body) = response.unwrap_or((
+        "404 Not Found",
+        TEXT_CONTENT_TYPE,
+        String::from("Not Found\n"),
+    ));
+
+    stream.write_all(
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: \
+             {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .as_bytes(),
+    )
+}
+
+fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+};
+
+use ipnetwork::IpNetwork;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::{
+    allow_list::COMMENT_PREFIX,
+    Config,
+};
+
+const DENY_ALL_DIRECTIVE: &str = "deny all;";
+pub const CIDR_PLACEHOLDER: &str = "{cidr}";
+pub(crate) const DEFAULT_LINE_TEMPLATE: &str = "allow {cidr};";
+pub(crate) const DEFAULT_IPSET_NAME: &str = "github";
+pub(crate) const DEFAULT_GEO_VARIABLE: &str = "github";
+pub(crate) const DEFAULT_GEO_VALUE: &str = "1";
+/// Value of `geo` variable for addresses outside of allow list
+const GEO_DEFAULT_VALUE: &str = "0";
+
+/// Kind of software allow file is written for
+#[derive(Debug, Default, Clone, Copy)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Nginx `allow` directives
+    #[default]
+    Nginx,
+    /// Nginx `geo` block setting variable for allowed addresses
+    #[serde(rename = "nginx-geo")]
+    NginxGeo,
+    /// Elements of nftables set
+    Nftables,
+    /// `ipset restore` commands
+    Ipset,
+    /// Bare CIDR per line for anything that reads flat list
+    Plain,
+}
+
+/// Serializes allow list into allow file content. Reading is format agnostic: every word that
+/// parses as CIDR is picked up, so renderer doesn't have to parse its own output
+pub trait AllowListRenderer: std::fmt::Debug {
+    /// Writes allow file content for `cidrs`, which are already in stable order
+    fn render(&self, cidrs: &[IpNetwork], writer: &mut dyn Write) -> std::io::Result<()>;
+
+    /// Same as `render`, but formats that support comments note where CIDR came from with `tags`
+    fn render_tagged(
+        &self,
+        cidrs: &[IpNetwork],
+        _tags: &HashMap<IpNetwork, String>,
+        writer: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        self.render(cidrs, writer)
+    }
+}
+
+/// Builds renderer for configured `output_format`
+pub fn renderer_from_config(config: &Config) -> Box<dyn AllowListRenderer> {
+    match config.output_format {
+        OutputFormat::Nginx => Box::new(NginxRenderer {
+            line_template: config
+                .line_template
+                .clone()
+                .unwrap_or_else(|| DEFAULT_LINE_TEMPLATE.to_string()),
+            deny_all:      config.deny_all,
+        }),
+        OutputFormat::NginxGeo => Box::new(NginxGeoRenderer {
+            variable: config
+                .geo_variable
+                .as_deref()
+                .unwrap_or(DEFAULT_GEO_VARIABLE)
+                .trim_start_matches('$')
+                .to_string(),
+            value:    config
+                .geo_value
+                .clone()
+                .unwrap_or_else(|| DEFAULT_GEO_VALUE.to_string()),
+        }),
+        OutputFormat::Nftables => Box::new(NftablesRenderer),
+        OutputFormat::Ipset => Box::new(IpsetRenderer {
+            set_name: config
+                .ipset_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_IPSET_NAME.to_string()),
+        }),
+        OutputFormat::Plain => Box::new(PlainRenderer),
+    }
+}
+
+/// Line per CIDR made from `line_template`, optionally followed by `deny all;`
+#[derive(Debug, Clone)]
+pub struct NginxRenderer {
+    /// Template of line written for every CIDR
+    pub line_template: String,
+    /// Append `deny all;` directive after allow directives
+    pub deny_all:      bool,
+}
+
+impl Default for NginxRenderer {
+    fn default() -> Self {
+        Self {
+            line_template: DEFAULT_LINE_TEMPLATE.to_string(),
+            deny_all:      false,
+        }
+    }
+}
+
+impl NginxRenderer {
+    pub fn render_line(&self, cidr: &IpNetwork) -> String {
+        self.line_template
+            .replace(CIDR_PLACEHOLDER, &cidr.to_string())
+    }
+}
+
+impl AllowListRenderer for NginxRenderer {
+    fn render(&self, cidrs: &[IpNetwork], writer: &mut dyn Write) -> std::io::Result<()> {
+        self.render_tagged(cidrs, &HashMap::new(), writer)
+    }
+
+    fn render_tagged(
+        &self,
+        cidrs: &[IpNetwork],
+        tags: &HashMap<IpNetwork, String>,
+        writer: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        for cidr in cidrs {
+            match tags.get(cidr) {
+                Some(tag) => writer.write_fmt(format_args!(
+                    "{}  {} {}\n",
+                    self.render_line(cidr),
+                    COMMENT_PREFIX,
+                    tag
+                ))?,
+                None => writer.write_fmt(format_args!("{}\n", self.render_line(cidr)))?,
+            }
+        }
+
+        if self.deny_all {
+            writer.write_fmt(format_args!("{}\n", DENY_ALL_DIRECTIVE))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// `geo $<variable> { ... }` block setting `variable` to `value` for allowed CIDRs and to `0`
+/// for everything else, so location can branch on it instead of using access module
+#[derive(Debug, Clone)]
+pub struct NginxGeoRenderer {
+    /// Name of variable without `$`
+    pub variable: String,
+    pub value:    String,
+}
+
+impl AllowListRenderer for NginxGeoRenderer {
+    fn render(&self, cidrs: &[IpNetwork], writer: &mut dyn Write) -> std::io::Result<()> {
+        writer.write_fmt(format_args!("geo ${} {{\n", self.variable))?;
+        writer.write_fmt(format_args!("    default {};\n", GEO_DEFAULT_VALUE))?;
+
+        for cidr in cidrs {
+            writer.write_fmt(format_args!("    {} {};\n", cidr, self.value))?;
+        }
+
+        writer.write_all(b"}\n")
+    }
+}
+
+/// `elements = { ... }` statement meant to be included into nftables set definition. Set can
+/// hold addresses of single family, so `ip_version` has to match set type
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NftablesRenderer;
+
+impl AllowListRenderer for NftablesRenderer {
+    fn render(&self, cidrs: &[IpNetwork], writer: &mut dyn Write) -> std::io::Result<()> {
+        writer.write_all(b"elements = {\n")?;
+
+        for cidr in cidrs {
+            writer.write_fmt(format_args!("    {},\n", cidr))?;
+        }
+
+        writer.write_all(b"}\n")
+    }
+}
+
+/// `add <set> <cidr>` lines for `ipset restore`. Set holds addresses of single family, so IPv4
+/// CIDRs go to `<set_name>_v4` and IPv6 ones to `<set_name>_v6`
+#[derive(Debug, Clone)]
+pub struct IpsetRenderer {
+    pub set_name: String,
+}
+
+impl AllowListRenderer for IpsetRenderer {
+    fn render(&self, cidrs: &[IpNetwork], writer: &mut dyn Write) -> std::io::Result<()> {
+        for cidr in cidrs {
+            let family = match cidr {
+                IpNetwork::V4(_) => "v4",
+                IpNetwork::V6(_) => "v6",
+            };
+
+            writer.write_fmt(format_args!("add {}_{} {}\n", self.set_name, family, cidr))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// CIDR per line without any syntax around it, e.g. for cloud firewall scripts
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PlainRenderer;
+
+impl AllowListRenderer for PlainRenderer {
+    fn render(&self, cidrs: &[IpNetwork], writer: &mut dyn Write) -> std::io::Result<()> {
+        for cidr in cidrs {
+            writer.write_fmt(format_args!("{}\n", cidr))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use ipnetwork::IpNetwork;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::allow_list::{
+    normalized_cidr,
+    sorted_cidrs,
+    temp_file_path,
+    CidrSet,
+};
+
+/// Last successfully written allow list, kept next to allow file to survive restarts while
+/// GitHub API is down
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    pub cidrs:     CidrSet,
+    /// Size of the largest allow list ever written, `min_entries` floor grows with it
+    pub max_cidrs: usize,
+}
+
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    cidrs:     Vec<IpNetwork>,
+    /// Missing in state files written before `min_entries` existed
+    #[serde(default)]
+    max_cidrs: usize,
+}
+
+/// Path of state file belonging to `allow_file`
+pub fn state_file_path(allow_file: &str) -> PathBuf {
+    PathBuf::from(format!("{}.state", allow_file))
+}
+
+/// Reads state file, `None` if it doesn't exist yet
+pub fn load(file_path: &Path) -> Result<Option<State>, anyhow::Error> {
+    let content = match std::fs::read_to_string(file_path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let state_file: StateFile = serde_json::from_str(&content)?;
+    let cidrs: CidrSet = state_file.cidrs.into_iter().map(normalized_cidr).collect();
+
+    Ok(Some(State {
+        max_cidrs: state_file.max_cidrs.max(cidrs.len()),
+        cidrs,
+    }))
+}
+
+/// Atomically replaces state file with `state`
+pub fn save(file_path: &Path, state: &State) -> Result<(), anyhow::Error> {
+    let temp_file_path = temp_file_path(file_path);
+
+    let mut temp_file = File::create(&temp_file_path)?;
+
+    serde_json::to_writer(
+        &mut temp_file,
+        &StateFile {
+            cidrs:     sorted_cidrs(&state.cidrs),
+            max_cidrs: state.max_cidrs,
+        },
+    )?;
+    temp_file.write_all(b"\n")?;
+    temp_file.sync_all()?;
+
+    std::fs::rename(&temp_file_path, file_path)?;
+
+    Ok(())
+}
@@ -0,0 +1,31 @@
+//! systemd service manager notifications, no-ops unless built with `systemd` feature and run by
+//! systemd with `NOTIFY_SOCKET` set
+
+/// Tells systemd that service startup is finished
+pub fn notify_ready() {
+    #[cfg(feature = "systemd")]
+    notify(sd_notify::NotifyState::Ready);
+}
+
+/// Asks systemd to wait `duration` longer for startup to finish
+pub fn extend_startup_timeout(duration: std::time::Duration) {
+    #[cfg(feature = "systemd")]
+    notify(sd_notify::NotifyState::ExtendTimeoutUsec(
+        u32::try_from(duration.as_micros()).unwrap_or(u32::MAX),
+    ));
+    #[cfg(not(feature = "systemd"))]
+    let _ = duration;
+}
+
+/// Resets systemd watchdog timer
+pub fn notify_watchdog() {
+    #[cfg(feature = "systemd")]
+    notify(sd_notify::NotifyState::Watchdog);
+}
+
+#[cfg(feature = "systemd")]
+fn notify(state: sd_notify::NotifyState) {
+    if let Err(err) = sd_notify::notify(false, &[state]) {
+        log::warn!("Failed to notify systemd. {:#}", err);
+    }
+}
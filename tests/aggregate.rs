@@ -0,0 +1,114 @@
+//! Aggregation of adjacent and overlapping CIDRs
+
+use github_nginx_hooker::{
+    aggregate::aggregate,
+    AllowFileFormat,
+    AllowList,
+    CidrSet,
+};
+
+fn cidrs(cidrs: &[&str]) -> CidrSet {
+    cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+}
+
+#[test]
+fn siblings_are_merged_into_parent() {
+    assert_eq!(
+        aggregate(&cidrs(&["192.30.252.0/24", "192.30.253.0/24"])),
+        cidrs(&["192.30.252.0/23"])
+    );
+}
+
+#[test]
+fn adjacent_networks_of_different_parents_are_kept() {
+    let not_siblings = cidrs(&["192.30.253.0/24", "192.30.254.0/24"]);
+
+    assert_eq!(aggregate(&not_siblings), not_siblings);
+}
+
+#[test]
+fn subsumed_networks_are_dropped() {
+    assert_eq!(
+        aggregate(&cidrs(&[
+            "192.30.252.0/22",
+            "192.30.253.0/24",
+            "192.30.254.128/25"
+        ])),
+        cidrs(&["192.30.252.0/22"])
+    );
+}
+
+#[test]
+fn merges_repeat_until_nothing_changes() {
+    assert_eq!(
+        aggregate(&cidrs(&[
+            "185.199.108.0/24",
+            "185.199.109.0/24",
+            "185.199.110.0/24",
+            "185.199.111.0/25",
+            "185.199.111.128/25",
+        ])),
+        cidrs(&["185.199.108.0/22"])
+    );
+}
+
+#[test]
+fn halves_of_address_space_merge_into_default_route() {
+    assert_eq!(
+        aggregate(&cidrs(&["0.0.0.0/1", "128.0.0.0/1", "10.0.0.0/8"])),
+        cidrs(&["0.0.0.0/0"])
+    );
+    assert_eq!(
+        aggregate(&cidrs(&["::/0", "2a0a:a440::/29"])),
+        cidrs(&["::/0"])
+    );
+}
+
+#[test]
+fn ipv6_networks_are_merged_separately_from_ipv4() {
+    assert_eq!(
+        aggregate(&cidrs(&[
+            "2a0a:a440::/30",
+            "2a0a:a444::/30",
+            "192.30.252.0/24",
+            "192.30.253.0/24",
+        ])),
+        cidrs(&["2a0a:a440::/29", "192.30.252.0/23"])
+    );
+}
+
+#[test]
+fn aggregated_allow_file_is_unchanged_on_next_update() {
+    let file_path = std::env::temp_dir().join(format!(
+        "github_nginx_hooker_test_aggregate_{}.conf",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&file_path);
+    let fetched = cidrs(&[
+        "192.30.252.0/24",
+        "192.30.253.0/24",
+        "192.30.254.0/23",
+        "2a0a:a440::/29",
+    ]);
+
+    let mut allow_list =
+        AllowList::load(file_path.to_str().unwrap(), AllowFileFormat::default()).unwrap();
+    let diff = allow_list.update(aggregate(&fetched), false).unwrap();
+
+    assert_eq!(diff.added, cidrs(&["192.30.252.0/22", "2a0a:a440::/29"]));
+
+    // Restart reads aggregated CIDRs back and compares them with aggregated fetched ones
+    let mut allow_list =
+        AllowList::load(file_path.to_str().unwrap(), AllowFileFormat::default()).unwrap();
+
+    assert!(allow_list
+        .update(aggregate(&fetched), false)
+        .unwrap()
+        .is_empty());
+    assert!(allow_list
+        .update(aggregate(&fetched), false)
+        .unwrap()
+        .is_empty());
+
+    std::fs::remove_file(&file_path).unwrap();
+}
@@ -0,0 +1,267 @@
+//! Change detection of allow file updates
+
+use std::collections::HashMap;
+
+use github_nginx_hooker::{
+    AllowFileFormat,
+    AllowList,
+    AllowListDiff,
+    CidrSet,
+};
+
+fn cidrs(cidrs: &[&str]) -> CidrSet {
+    cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+}
+
+/// Allow list loaded from allow file of single test with `content`
+fn allow_list(name: &str, content: &str) -> (AllowList, std::path::PathBuf) {
+    let file_path = std::env::temp_dir().join(format!(
+        "github_nginx_hooker_test_allow_list_{}_{}.conf",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(&file_path, content).unwrap();
+
+    let allow_list =
+        AllowList::load(file_path.to_str().unwrap(), AllowFileFormat::default()).unwrap();
+
+    (allow_list, file_path)
+}
+
+#[test]
+fn first_population_adds_every_cidr() {
+    let (mut allow_list, file_path) = allow_list("first_population", "");
+
+    let diff = allow_list
+        .update(cidrs(&["192.30.252.0/22", "2a0a:a440::/29"]), false)
+        .unwrap();
+
+    assert_eq!(diff.added, cidrs(&["192.30.252.0/22", "2a0a:a440::/29"]));
+    assert!(diff.removed.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "allow 192.30.252.0/22;\nallow 2a0a:a440::/29;\n"
+    );
+
+    std::fs::remove_file(&file_path).unwrap();
+}
+
+#[test]
+fn pure_additions_keep_existing_cidrs() {
+    let (mut allow_list, file_path) = allow_list("pure_additions", "allow 192.30.252.0/22;\n");
+
+    let diff = allow_list
+        .update(cidrs(&["192.30.252.0/22", "185.199.108.0/22"]), false)
+        .unwrap();
+
+    assert_eq!(diff.added, cidrs(&["185.199.108.0/22"]));
+    assert!(diff.removed.is_empty());
+
+    std::fs::remove_file(&file_path).unwrap();
+}
+
+#[test]
+fn pure_removals_keep_remaining_cidrs() {
+    let (mut allow_list, file_path) = allow_list(
+        "pure_removals",
+        "allow 185.199.108.0/22;\nallow 192.30.252.0/22;\n",
+    );
+
+    let diff = allow_list
+        .update(cidrs(&["192.30.252.0/22"]), false)
+        .unwrap();
+
+    assert!(diff.added.is_empty());
+    assert_eq!(diff.removed, cidrs(&["185.199.108.0/22"]));
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "allow 192.30.252.0/22;\n"
+    );
+
+    std::fs::remove_file(&file_path).unwrap();
+}
+
+#[test]
+fn mixed_update_reports_both_sides_and_same_set_is_unchanged() {
+    let (mut allow_list, file_path) =
+        allow_list("mixed", "allow 185.199.108.0/22;\nallow 192.30.252.0/22;\n");
+
+    let diff = allow_list
+        .update(cidrs(&["192.30.252.0/22", "140.82.112.0/20"]), false)
+        .unwrap();
+
+    assert_eq!(diff.added, cidrs(&["140.82.112.0/20"]));
+    assert_eq!(diff.removed, cidrs(&["185.199.108.0/22"]));
+    assert!(!diff.is_empty());
+
+    let diff = allow_list
+        .update(cidrs(&["192.30.252.0/22", "140.82.112.0/20"]), false)
+        .unwrap();
+
+    assert!(diff.is_empty());
+
+    std::fs::remove_file(&file_path).unwrap();
+}
+
+#[test]
+fn interrupted_write_leaves_allow_file_intact() {
+    let (mut allow_list, file_path) = allow_list("interrupted_write", "allow 192.30.252.0/22;\n");
+    let temp_file_path = format!("{}.tmp", file_path.display());
+
+    // Temporary file can't be written, e.g. process crashed or disk failed halfway
+    std::fs::create_dir(&temp_file_path).unwrap();
+
+    allow_list
+        .update(cidrs(&["185.199.108.0/22"]), false)
+        .unwrap_err();
+
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "allow 192.30.252.0/22;\n"
+    );
+
+    // Partial temporary file left by crashed process doesn't get in the way of the next save
+    std::fs::remove_dir(&temp_file_path).unwrap();
+    std::fs::write(&temp_file_path, "allow 185.199.").unwrap();
+    let mut allow_list =
+        AllowList::load(file_path.to_str().unwrap(), AllowFileFormat::default()).unwrap();
+
+    assert_eq!(allow_list.cidrs(), &cidrs(&["192.30.252.0/22"]));
+
+    allow_list
+        .update(cidrs(&["185.199.108.0/22"]), false)
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "allow 185.199.108.0/22;\n"
+    );
+    assert!(!std::path::Path::new(&temp_file_path).exists());
+
+    std::fs::remove_file(&file_path).unwrap();
+}
+
+#[test]
+fn dry_run_leaves_allow_list_as_it_is() {
+    let (mut allow_list, file_path) = allow_list("dry_run", "allow 192.30.252.0/22;\n");
+
+    let diff = allow_list
+        .update(cidrs(&["185.199.108.0/22"]), true)
+        .unwrap();
+
+    assert_eq!(diff.added, cidrs(&["185.199.108.0/22"]));
+    assert_eq!(allow_list.cidrs(), &cidrs(&["192.30.252.0/22"]));
+
+    // The same change is still reported by the next live update
+    let diff = allow_list
+        .update(cidrs(&["185.199.108.0/22"]), false)
+        .unwrap();
+
+    assert_eq!(diff.removed, cidrs(&["192.30.252.0/22"]));
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "allow 185.199.108.0/22;\n"
+    );
+
+    std::fs::remove_file(&file_path).unwrap();
+}
+
+#[test]
+fn changed_tags_get_allow_file_rewritten() {
+    let (mut allow_list, file_path) = allow_list("tags", "allow 192.30.252.0/22;\n");
+    let tags = |tag: &str| HashMap::from([("192.30.252.0/22".parse().unwrap(), tag.to_string())]);
+
+    // tag_categories enabled for allow file written without tags
+    allow_list.set_tags(tags("hooks"));
+    let diff = allow_list
+        .update(cidrs(&["192.30.252.0/22"]), false)
+        .unwrap();
+
+    assert!(diff.is_reformatted);
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "allow 192.30.252.0/22;  # hooks\n"
+    );
+
+    // Categories of CIDR changed
+    allow_list.set_tags(tags("hooks, web"));
+    let diff = allow_list
+        .update(cidrs(&["192.30.252.0/22"]), false)
+        .unwrap();
+
+    assert!(diff.is_reformatted);
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "allow 192.30.252.0/22;  # hooks, web\n"
+    );
+
+    // Restart with the same tags reads them back from the file
+    let mut allow_list =
+        AllowList::load(file_path.to_str().unwrap(), AllowFileFormat::default()).unwrap();
+    allow_list.set_tags(tags("hooks, web"));
+
+    assert!(allow_list
+        .update(cidrs(&["192.30.252.0/22"]), false)
+        .unwrap()
+        .is_empty());
+
+    // tag_categories disabled
+    allow_list.set_tags(HashMap::new());
+    let diff = allow_list
+        .update(cidrs(&["192.30.252.0/22"]), false)
+        .unwrap();
+
+    assert!(diff.is_reformatted);
+    assert_eq!(
+        std::fs::read_to_string(&file_path).unwrap(),
+        "allow 192.30.252.0/22;\n"
+    );
+
+    std::fs::remove_file(&file_path).unwrap();
+}
+
+#[test]
+fn merge_unions_changes_of_several_allow_files() {
+    let mut diff = AllowListDiff {
+        added:          cidrs(&["192.30.252.0/22"]),
+        removed:        cidrs(&["10.0.0.0/8"]),
+        is_reformatted: false,
+    };
+
+    diff.merge(AllowListDiff {
+        added:          cidrs(&["2a0a:a440::/29"]),
+        removed:        CidrSet::default(),
+        is_reformatted: true,
+    });
+
+    assert_eq!(diff.added, cidrs(&["192.30.252.0/22", "2a0a:a440::/29"]));
+    assert_eq!(diff.removed, cidrs(&["10.0.0.0/8"]));
+    assert!(diff.is_reformatted);
+}
+
+#[test]
+fn chain_cancels_out_cidrs_added_and_removed_later() {
+    let mut diff = AllowListDiff {
+        added:          cidrs(&["192.30.252.0/22", "185.199.108.0/22"]),
+        removed:        cidrs(&["10.0.0.0/8"]),
+        is_reformatted: false,
+    };
+
+    diff.chain(AllowListDiff {
+        added:          cidrs(&["10.0.0.0/8", "140.82.112.0/20"]),
+        removed:        cidrs(&["185.199.108.0/22"]),
+        is_reformatted: false,
+    });
+
+    assert_eq!(diff.added, cidrs(&["192.30.252.0/22", "140.82.112.0/20"]));
+    assert!(diff.removed.is_empty());
+    assert!(!diff.is_empty());
+
+    diff.chain(AllowListDiff {
+        added:          CidrSet::default(),
+        removed:        cidrs(&["192.30.252.0/22", "140.82.112.0/20"]),
+        is_reformatted: false,
+    });
+
+    assert!(diff.is_empty());
+}
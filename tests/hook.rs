@@ -0,0 +1,94 @@
+//! Running `after_update_hook`
+
+use github_nginx_hooker::{
+    test_after_update_hook,
+    Config,
+};
+
+/// Reads config of single test with `after_update_hook` set to TOML value `hook`
+fn read_config(name: &str, hook: &str) -> Result<Config, github_nginx_hooker::ConfigReadError> {
+    let config_path = std::env::temp_dir().join(format!(
+        "github_nginx_hooker_test_hook_{}_{}.toml",
+        name,
+        std::process::id()
+    ));
+
+    std::fs::write(
+        &config_path,
+        format!(
+            "allow_file = \"allow.conf\"\nrepeat = 60\nafter_update_hook = {}\n",
+            hook
+        ),
+    )
+    .unwrap();
+
+    let config = Config::read_from_file(config_path.to_str().unwrap());
+    std::fs::remove_file(&config_path).unwrap();
+
+    config
+}
+
+#[test]
+fn nonzero_exit_code_is_reported() {
+    let config = read_config("exit_code", "\"exit 3\"").unwrap();
+
+    let err = test_after_update_hook(&config).unwrap_err();
+
+    assert!(
+        format!("{:#}", err).contains("after_update_hook exited with code 3"),
+        "{:#}",
+        err
+    );
+}
+
+#[test]
+fn hook_killed_by_signal_is_reported() {
+    let config = read_config("signal", "\"kill -9 $$\"").unwrap();
+
+    let err = test_after_update_hook(&config).unwrap_err();
+
+    assert!(
+        format!("{:#}", err).contains("after_update_hook was killed by signal 9"),
+        "{:#}",
+        err
+    );
+}
+
+#[test]
+fn string_hook_is_run_through_shell() {
+    let marker = std::env::temp_dir().join(format!(
+        "github_nginx_hooker_test_hook_shell_{}",
+        std::process::id()
+    ));
+    let hook = format!("\"echo changed=$HOOKER_CHANGED > {}\"", marker.display());
+    let config = read_config("shell", &hook).unwrap();
+
+    test_after_update_hook(&config).unwrap();
+
+    assert_eq!(std::fs::read_to_string(&marker).unwrap(), "changed=true\n");
+    std::fs::remove_file(&marker).unwrap();
+}
+
+#[test]
+fn argv_hook_is_run_without_shell() {
+    // Shell would split this into two commands
+    let marker = std::env::temp_dir().join(format!(
+        "github_nginx_hooker_test_hook_argv_{};true",
+        std::process::id()
+    ));
+    let hook = format!("[\"touch\", \"{}\"]", marker.display());
+    let config = read_config("argv", &hook).unwrap();
+
+    test_after_update_hook(&config).unwrap();
+
+    assert!(marker.exists());
+    std::fs::remove_file(&marker).unwrap();
+}
+
+#[test]
+fn empty_argv_hook_is_rejected() {
+    assert!(matches!(
+        read_config("empty_argv", "[]"),
+        Err(github_nginx_hooker::ConfigReadError::EmptyHookCommand)
+    ));
+}
@@ -0,0 +1,1136 @@
+//! Update cycles against fake GitHub meta API served from local socket
+
+use std::{
+    io::{
+        BufRead,
+        BufReader,
+        Write,
+    },
+    net::TcpListener,
+    path::PathBuf,
+    sync::{
+        mpsc,
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use github_nginx_hooker::{
+    clock::MockClock,
+    Config,
+    FetchError,
+    Hooker,
+};
+
+const META_JSON: &str = r#"{
+    "hooks": ["192.30.252.0/22", "185.199.108.0/22", "2a0a:a440::/29"],
+    "web": ["140.82.112.0/20"]
+}"#;
+const META_ALLOW_FILE: &str =
+    "allow 185.199.108.0/22;\nallow 192.30.252.0/22;\nallow 2a0a:a440::/29;\n";
+
+/// Canned HTTP response of fake meta API
+struct Response {
+    status:  &'static str,
+    headers: Vec<(&'static str, String)>,
+    body:    &'static str,
+    /// Connection is held this long without any response and closed, e.g. to time request out
+    hang:    Option<Duration>,
+}
+
+impl Response {
+    fn ok(body: &'static str) -> Self {
+        Self {
+            status: "200 OK",
+            headers: vec![("Content-Type", "application/json".to_string())],
+            body,
+            hang: None,
+        }
+    }
+
+    fn hang(duration: Duration) -> Self {
+        Self {
+            hang: Some(duration),
+            ..Self::ok("")
+        }
+    }
+}
+
+/// Fake meta API answering requests with `responses` in order. Request heads are sent to the
+/// returned receiver
+struct MetaServer {
+    base_url: String,
+    requests: mpsc::Receiver<String>,
+    thread:   JoinHandle<()>,
+}
+
+impl MetaServer {
+    fn start(responses: Vec<Response>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+
+        let thread = std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+
+                let mut request_head = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    request_head.push_str(&line);
+                }
+                sender.send(request_head).unwrap();
+
+                if let Some(hang) = response.hang {
+                    std::thread::sleep(hang);
+                    continue;
+                }
+
+                write!(stream, "HTTP/1.1 {}\r\n", response.status).unwrap();
+                for (name, value) in &response.headers {
+                    write!(stream, "{}: {}\r\n", name, value).unwrap();
+                }
+                write!(
+                    stream,
+                    "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.body.len(),
+                    response.body
+                )
+                .unwrap();
+            }
+        });
+
+        Self {
+            base_url,
+            requests,
+            thread,
+        }
+    }
+
+    /// Waits for all responses to be served and returns heads of received requests
+    fn finish(self) -> Vec<String> {
+        self.thread.join().unwrap();
+        self.requests.try_iter().collect()
+    }
+}
+
+/// Empty directory for allow file and config of single test
+fn test_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!(
+        "github_nginx_hooker_test_{}_{}",
+        name,
+        std::process::id()
+    ));
+
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+
+    directory
+}
+
+/// Writes config pointing at `server` with `extra` keys into `directory` and loads hooker from it.
+/// `after_update_hook` creates `hook_ran` file in `directory`
+fn hooker(directory: &std::path::Path, server: &MetaServer, extra: &str) -> Hooker {
+    let config_path = directory.join("hooker.toml");
+
+    std::fs::write(
+        &config_path,
+        format!(
+            "token = \"test\"\nallow_file = \"{}\"\nafter_update_hook = \"touch {}\"\nrepeat = \
+             60\napi_base_url = \"{}\"\n{}\n",
+            directory.join("allow.conf").display(),
+            directory.join("hook_ran").display(),
+            server.base_url,
+            extra
+        ),
+    )
+    .unwrap();
+
+    Hooker::from_config_file(config_path.to_str().unwrap()).unwrap()
+}
+
+#[test]
+fn writes_allow_lines_of_fetched_cidrs() {
+    let directory = test_directory("writes_allow_lines");
+    let server = MetaServer::start(vec![Response::ok(META_JSON)]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert_eq!(diff.added.len(), 3);
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        META_ALLOW_FILE
+    );
+
+    let requests = server.finish();
+    assert!(requests[0].starts_with("GET /meta "));
+    assert!(requests[0].contains("authorization: token test\r\n"));
+}
+
+#[test]
+fn equivalent_cidr_forms_are_unchanged() {
+    let directory = test_directory("equivalent_forms");
+    std::fs::write(directory.join("allow.conf"), META_ALLOW_FILE).unwrap();
+    let server = MetaServer::start(vec![Response::ok(
+        r#"{"hooks": ["192.30.252.1/22", "185.199.108.0/22", "2A0A:A440:0:0:0:0:0:0/29"]}"#,
+    )]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert!(diff.is_empty(), "{:?}", diff);
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        META_ALLOW_FILE
+    );
+
+    server.finish();
+}
+
+#[test]
+fn not_modified_reuses_previous_meta_information() {
+    let directory = test_directory("not_modified");
+    let mut first_response = Response::ok(META_JSON);
+    first_response
+        .headers
+        .push(("ETag", "\"meta-etag\"".to_string()));
+    let server = MetaServer::start(vec![
+        first_response,
+        Response {
+            status:  "304 Not Modified",
+            headers: vec![("ETag", "\"meta-etag\"".to_string())],
+            body:    "",
+            hang:    None,
+        },
+    ]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    hooker.update_cycle(false).unwrap();
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert!(diff.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        META_ALLOW_FILE
+    );
+
+    let requests = server.finish();
+    assert!(!requests[0].contains("if-none-match"));
+    assert!(requests[1].contains("if-none-match: \"meta-etag\"\r\n"));
+}
+
+#[test]
+fn missing_category_keeps_allow_file_unless_not_required() {
+    let directory = test_directory("missing_category");
+    std::fs::write(directory.join("allow.conf"), "allow 10.0.0.0/8;\n").unwrap();
+    let web_only = r#"{"web": ["140.82.112.0/20"]}"#;
+    let server = MetaServer::start(vec![Response::ok(web_only), Response::ok(web_only)]);
+    let categories = "categories = [\"hooks\", \"web\"]";
+    let mut required_hooker = hooker(&directory, &server, categories);
+
+    let err = required_hooker.update_cycle(false).unwrap_err();
+
+    assert!(format!("{:#}", err).contains("GitHub meta information has no [hooks] category"));
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 10.0.0.0/8;\n"
+    );
+
+    let extra = format!("{}\nrequire_categories = false", categories);
+    let mut optional_hooker = hooker(&directory, &server, &extra);
+
+    optional_hooker.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 140.82.112.0/20;\n"
+    );
+
+    server.finish();
+}
+
+#[test]
+fn rate_limited_response_is_rate_limited_error() {
+    let directory = test_directory("rate_limited");
+    let reset = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 120;
+    let server = MetaServer::start(vec![Response {
+        status:  "403 Forbidden",
+        headers: vec![
+            ("X-RateLimit-Remaining", "0".to_string()),
+            ("X-RateLimit-Reset", reset.to_string()),
+        ],
+        body:    "{\"message\": \"API rate limit exceeded\"}",
+        hang:    None,
+    }]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    let err = hooker.update_cycle(false).unwrap_err();
+
+    let retry_after = match err.downcast_ref::<FetchError>() {
+        Some(FetchError::RateLimited {
+            retry_after: Some(retry_after),
+        }) => *retry_after,
+        _ => panic!(
+            "403 with exhausted rate limit is not RateLimited: {:#}",
+            err
+        ),
+    };
+    assert!(retry_after <= Duration::from_secs(120));
+    assert!(retry_after > Duration::from_secs(100));
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        ""
+    );
+
+    server.finish();
+}
+
+#[test]
+fn rate_limit_reset_in_the_past_still_waits() {
+    let directory = test_directory("rate_limit_reset_passed");
+    let reset = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        - 30;
+    let server = MetaServer::start(vec![
+        Response {
+            status:  "403 Forbidden",
+            headers: vec![
+                ("X-RateLimit-Remaining", "0".to_string()),
+                ("X-RateLimit-Reset", reset.to_string()),
+            ],
+            body:    "{\"message\": \"API rate limit exceeded\"}",
+            hang:    None,
+        },
+        Response {
+            status:  "429 Too Many Requests",
+            headers: vec![("Retry-After", "0".to_string())],
+            body:    "{\"message\": \"API rate limit exceeded\"}",
+            hang:    None,
+        },
+    ]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    for _ in 0..2 {
+        let err = hooker.update_cycle(false).unwrap_err();
+
+        assert!(
+            matches!(
+                err.downcast_ref::<FetchError>(),
+                Some(FetchError::RateLimited {
+                    retry_after: Some(retry_after),
+                }) if *retry_after == Duration::from_secs(60)
+            ),
+            "{:#}",
+            err
+        );
+    }
+
+    server.finish();
+}
+
+#[test]
+fn unauthorized_response_is_unauthorized_error() {
+    let directory = test_directory("unauthorized");
+    let server = MetaServer::start(vec![Response {
+        status:  "401 Unauthorized",
+        headers: vec![],
+        body:    "{\"message\": \"Bad credentials\"}",
+        hang:    None,
+    }]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    let err = hooker.update_cycle(false).unwrap_err();
+
+    assert!(matches!(
+        err.downcast_ref::<FetchError>(),
+        Some(FetchError::Unauthorized)
+    ));
+
+    server.finish();
+}
+
+#[test]
+fn stalled_response_times_out_instead_of_hanging() {
+    let directory = test_directory("request_timeout");
+    let server = MetaServer::start(vec![Response::hang(Duration::from_secs(3))]);
+    let mut hooker = hooker(&directory, &server, "request_timeout_secs = 1");
+    let started_at = std::time::Instant::now();
+
+    let err = hooker.update_cycle(false).unwrap_err();
+
+    assert!(started_at.elapsed() < Duration::from_secs(3));
+    assert!(matches!(
+        err.downcast_ref::<FetchError>(),
+        Some(FetchError::Network { source, .. }) if source.is_timeout()
+    ));
+
+    server.finish();
+}
+
+#[test]
+fn malformed_json_keeps_allow_file() {
+    let directory = test_directory("malformed_json");
+    std::fs::write(directory.join("allow.conf"), "allow 10.0.0.0/8;\n").unwrap();
+    let server = MetaServer::start(vec![Response::ok("{\"hooks\": [\"192.30.252.0/22\"")]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    let err = hooker.update_cycle(false).unwrap_err();
+
+    assert!(format!("{:#}", err).contains("Failed to deserialize GitHub meta information"));
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 10.0.0.0/8;\n"
+    );
+
+    server.finish();
+}
+
+#[test]
+fn cache_control_max_age_stretches_poll_interval() {
+    let directory = test_directory("max_age");
+    let mut response = Response::ok(META_JSON);
+    response
+        .headers
+        .push(("Cache-Control", "public, max-age=300".to_string()));
+    let server = MetaServer::start(vec![response]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    assert_eq!(hooker.poll_interval(), Duration::from_secs(60));
+    hooker.update_cycle(false).unwrap();
+    assert_eq!(hooker.poll_interval(), Duration::from_secs(300));
+
+    server.finish();
+}
+
+#[test]
+fn fetch_below_historical_floor_keeps_allow_file() {
+    let directory = test_directory("min_entries");
+    std::fs::write(directory.join("allow.conf"), "allow 10.0.0.0/8;\n").unwrap();
+    std::fs::write(
+        directory.join("allow.conf.state"),
+        r#"{"cidrs": ["10.0.0.0/8"], "max_cidrs": 10}"#,
+    )
+    .unwrap();
+    let server = MetaServer::start(vec![Response::ok(META_JSON)]);
+    let mut hooker = hooker(&directory, &server, "min_entries = 1");
+
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert!(diff.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 10.0.0.0/8;\n"
+    );
+
+    server.finish();
+}
+
+#[test]
+fn aggregated_shrink_is_refused() {
+    let directory = test_directory("aggregated_shrink");
+    let allow_file = "allow 140.82.112.0/20;\nallow 143.55.64.0/20;\nallow \
+                      185.199.108.0/22;\nallow 192.30.252.0/22;\n";
+    std::fs::write(directory.join("allow.conf"), allow_file).unwrap();
+    // As many CIDRs as allow file has, but they aggregate into a single one
+    let server = MetaServer::start(vec![Response::ok(
+        r#"{"hooks": ["192.30.252.0/24", "192.30.253.0/24", "192.30.254.0/24",
+                      "192.30.255.0/24"]}"#,
+    )]);
+    let mut hooker = hooker(&directory, &server, "aggregate = true");
+
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert!(diff.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        allow_file
+    );
+
+    server.finish();
+}
+
+#[test]
+fn meta_url_is_used_verbatim() {
+    let directory = test_directory("meta_url");
+    let server = MetaServer::start(vec![Response::ok(META_JSON)]);
+    let extra = format!("meta_url = \"{}/mirror/github-meta.json\"", server.base_url);
+    let mut hooker = hooker(&directory, &server, &extra);
+
+    hooker.update_cycle(false).unwrap();
+
+    let requests = server.finish();
+    assert!(requests[0].starts_with("GET /mirror/github-meta.json "));
+}
+
+#[test]
+fn disk_full_keeps_allow_file_and_retries() {
+    let directory = test_directory("disk_full");
+    std::fs::write(directory.join("allow.conf"), "allow 10.0.0.0/8;\n").unwrap();
+    // Every write to /dev/full fails with ENOSPC
+    std::os::unix::fs::symlink("/dev/full", directory.join("allow.conf.tmp")).unwrap();
+    let server = MetaServer::start(vec![Response::ok(META_JSON), Response::ok(META_JSON)]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    let err = hooker.update_cycle(false).unwrap_err();
+
+    assert!(format!("{:#}", err).contains("No space left on device"));
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 10.0.0.0/8;\n"
+    );
+    assert!(!directory.join("allow.conf.tmp").exists());
+
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert_eq!(diff.added.len(), 3);
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        META_ALLOW_FILE
+    );
+
+    server.finish();
+}
+
+#[test]
+fn restart_while_github_is_down_restores_allow_file_from_state() {
+    let directory = test_directory("restore_state");
+    let server = MetaServer::start(vec![Response::ok(META_JSON)]);
+    hooker(&directory, &server, "").update_cycle(false).unwrap();
+    server.finish();
+
+    assert!(directory.join("allow.conf.state").exists());
+
+    // Allow file got truncated, e.g. by crash of other tool, and GitHub API is down on restart
+    std::fs::write(directory.join("allow.conf"), "").unwrap();
+    std::fs::remove_file(directory.join("hook_ran")).unwrap();
+    let server = MetaServer::start(vec![Response {
+        status:  "503 Service Unavailable",
+        headers: vec![],
+        body:    "",
+        hang:    None,
+    }]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    hooker.update_cycle(false).unwrap_err();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        META_ALLOW_FILE
+    );
+    assert!(directory.join("hook_ran").exists());
+
+    server.finish();
+}
+
+#[test]
+fn rejected_token_fails_over_to_next_one() {
+    let directory = test_directory("token_failover");
+    let server = MetaServer::start(vec![
+        Response {
+            status:  "401 Unauthorized",
+            headers: vec![],
+            body:    "{\"message\": \"Bad credentials\"}",
+            hang:    None,
+        },
+        Response::ok(META_JSON),
+    ]);
+    let mut hooker = hooker(&directory, &server, "tokens = [\"second\"]");
+
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert_eq!(diff.added.len(), 3);
+
+    let requests = server.finish();
+    assert!(requests[0].contains("authorization: token test\r\n"));
+    assert!(requests[1].contains("authorization: token second\r\n"));
+}
+
+#[test]
+fn hook_runs_only_for_hook_on_categories_changes() {
+    let directory = test_directory("hook_on_categories");
+    let marker = directory.join("hook_ran");
+    let server = MetaServer::start(vec![
+        Response::ok(META_JSON),
+        Response::ok(r#"{"hooks": ["192.30.252.0/22"], "web": ["140.82.112.0/20"]}"#),
+    ]);
+    let mut hooker = hooker(
+        &directory,
+        &server,
+        "categories = [\"hooks\", \"web\"]\nhook_on_categories = [\"web\"]",
+    );
+
+    hooker.update_cycle(false).unwrap();
+
+    assert!(marker.exists(), "adding web CIDR runs the hook");
+    std::fs::remove_file(&marker).unwrap();
+
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert_eq!(diff.removed.len(), 2);
+    assert!(
+        !marker.exists(),
+        "removing hooks CIDRs doesn't run the hook"
+    );
+
+    server.finish();
+}
+
+#[test]
+fn rewritten_allow_file_keeps_its_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = test_directory("keeps_permissions");
+    std::fs::write(directory.join("allow.conf"), "allow 10.0.0.0/8;\n").unwrap();
+    std::fs::set_permissions(
+        directory.join("allow.conf"),
+        std::fs::Permissions::from_mode(0o640),
+    )
+    .unwrap();
+    let server = MetaServer::start(vec![Response::ok(META_JSON)]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    hooker.update_cycle(false).unwrap();
+
+    let metadata = std::fs::metadata(directory.join("allow.conf")).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        META_ALLOW_FILE
+    );
+
+    server.finish();
+}
+
+#[test]
+fn symlinked_allow_file_is_written_through() {
+    let directory = test_directory("symlink");
+    std::fs::create_dir(directory.join("fragments")).unwrap();
+    std::os::unix::fs::symlink("fragments/github.conf", directory.join("allow.conf")).unwrap();
+    let server = MetaServer::start(vec![Response::ok(META_JSON)]);
+    let mut hooker = hooker(&directory, &server, "");
+
+    hooker.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_link(directory.join("allow.conf")).unwrap(),
+        PathBuf::from("fragments/github.conf")
+    );
+    assert_eq!(
+        std::fs::read_to_string(directory.join("fragments/github.conf")).unwrap(),
+        META_ALLOW_FILE
+    );
+
+    server.finish();
+}
+
+#[test]
+fn audit_log_records_changes_and_rotates() {
+    let directory = test_directory("audit_log");
+    let audit_log = directory.join("audit.jsonl");
+    let server = MetaServer::start(vec![
+        Response::ok(META_JSON),
+        Response::ok(r#"{"hooks": ["192.30.252.0/22", "185.199.108.0/22"]}"#),
+    ]);
+    let extra = format!(
+        "audit_log = \"{}\"\naudit_log_max_bytes = 1",
+        audit_log.display()
+    );
+    let mut hooker = hooker(&directory, &server, &extra);
+
+    hooker.update_cycle(false).unwrap();
+    hooker.update_cycle(false).unwrap();
+
+    let rotated: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(directory.join("audit.jsonl.1")).unwrap())
+            .unwrap();
+    assert_eq!(rotated["added"].as_array().unwrap().len(), 3);
+
+    let current: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&audit_log).unwrap()).unwrap();
+    assert_eq!(current["added"], serde_json::json!([]));
+    assert_eq!(current["removed"], serde_json::json!(["2a0a:a440::/29"]));
+    assert!(current["ts"].is_string());
+
+    server.finish();
+}
+
+#[test]
+fn category_urls_replace_meta_url_for_their_categories() {
+    let directory = test_directory("category_urls");
+    let server = MetaServer::start(vec![Response::ok("[\"140.82.112.0/20\"]")]);
+    let extra = format!(
+        "categories = [\"web\"]\n[category_urls]\nweb = \"{}/mirror/web.json\"",
+        server.base_url
+    );
+    let mut hooker = hooker(&directory, &server, &extra);
+
+    hooker.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 140.82.112.0/20;\n"
+    );
+
+    let requests = server.finish();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].starts_with("GET /mirror/web.json "));
+}
+
+#[test]
+fn category_urls_get_tokens_only_on_meta_url_origin() {
+    let directory = test_directory("category_url_tokens");
+    let server = MetaServer::start(vec![
+        Response {
+            status:  "401 Unauthorized",
+            headers: vec![],
+            body:    "{\"message\": \"Bad credentials\"}",
+            hang:    None,
+        },
+        Response::ok("[\"140.82.112.0/20\"]"),
+    ]);
+    let mirror = MetaServer::start(vec![Response::ok("[\"185.199.108.0/22\"]")]);
+    let extra = format!(
+        "tokens = [\"second\"]\ncategories = [\"hooks\", \"web\"]\n[category_urls]\nweb = \
+         \"{}/web.json\"\nhooks = \"{}/hooks.json\"",
+        server.base_url, mirror.base_url
+    );
+    let mut hooker = hooker(&directory, &server, &extra);
+
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert_eq!(diff.added.len(), 2);
+
+    let requests = server.finish();
+    assert!(requests[0].contains("authorization: token test\r\n"));
+    assert!(requests[1].contains("authorization: token second\r\n"));
+
+    let mirror_requests = mirror.finish();
+    assert!(!mirror_requests[0].contains("authorization:"));
+}
+
+#[test]
+fn unreachable_category_url_fails_cycle_unless_optional() {
+    let unreachable_url = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}/web.json", listener.local_addr().unwrap())
+    };
+    let extra = format!(
+        "categories = [\"hooks\", \"web\"]\n[category_urls]\nweb = \"{}\"",
+        unreachable_url
+    );
+
+    let directory = test_directory("required_category_url");
+    let server = MetaServer::start(vec![Response::ok(META_JSON)]);
+    let mut required_hooker = hooker(&directory, &server, &extra);
+
+    let err = required_hooker.update_cycle(false).unwrap_err();
+
+    assert!(matches!(
+        err.downcast_ref::<FetchError>(),
+        Some(FetchError::Category { category, .. }) if category == "web"
+    ));
+    assert!(!directory.join("hook_ran").exists());
+    server.finish();
+
+    let directory = test_directory("optional_category_url");
+    let server = MetaServer::start(vec![Response::ok(META_JSON)]);
+    let mut optional_hooker = hooker(
+        &directory,
+        &server,
+        &format!("optional_categories = [\"web\"]\n{}", extra),
+    );
+
+    optional_hooker.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        META_ALLOW_FILE
+    );
+    server.finish();
+}
+
+#[test]
+fn dedup_mapped_drops_mapped_duplicates_of_ipv4_cidrs() {
+    let directory = test_directory("dedup_mapped");
+    let server = MetaServer::start(vec![Response::ok(
+        r#"{"hooks": ["192.30.252.0/22", "::ffff:192.30.253.0/120", "::ffff:10.0.0.0/104"]}"#,
+    )]);
+    let mut hooker = hooker(&directory, &server, "dedup_mapped = true");
+
+    hooker.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 192.30.252.0/22;\nallow ::ffff:10.0.0.0/104;\n"
+    );
+
+    server.finish();
+}
+
+#[test]
+fn removed_cidr_is_kept_for_removal_grace_period() {
+    let directory = test_directory("removal_grace");
+    let shrunk_meta_json = r#"{"hooks": ["192.30.252.0/22", "2a0a:a440::/29"]}"#;
+    let server = MetaServer::start(vec![
+        Response::ok(META_JSON),
+        Response::ok(shrunk_meta_json),
+        Response::ok(shrunk_meta_json),
+        Response::ok(shrunk_meta_json),
+    ]);
+    let clock = Arc::new(MockClock::default());
+    let mut hooker = hooker(&directory, &server, "removal_grace_secs = \"1h\"")
+        .with_clock(Arc::clone(&clock) as _);
+
+    hooker.update_cycle(false).unwrap();
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert!(diff.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        META_ALLOW_FILE
+    );
+
+    clock.advance(Duration::from_secs(3599));
+    assert!(hooker.update_cycle(false).unwrap().is_empty());
+
+    clock.advance(Duration::from_secs(1));
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert_eq!(diff.removed.len(), 1);
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 192.30.252.0/22;\nallow 2a0a:a440::/29;\n"
+    );
+
+    server.finish();
+}
+
+#[test]
+fn newly_excluded_cidr_is_removed_without_removal_grace_period() {
+    let directory = test_directory("removal_grace_exclude");
+    std::fs::write(directory.join("allow.conf"), META_ALLOW_FILE).unwrap();
+    let server = MetaServer::start(vec![Response::ok(META_JSON)]);
+    let mut hooker = hooker(
+        &directory,
+        &server,
+        "removal_grace_secs = \"1h\"\nexclude = [\"185.199.108.0/22\"]\nip_version = \
+         \"v4\"\nmax_shrink_percent = 100",
+    );
+
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 192.30.252.0/22;\n"
+    );
+    assert_eq!(diff.removed.len(), 2);
+
+    server.finish();
+}
+
+#[test]
+fn reload_keeps_meta_cache_and_removal_grace_timers() {
+    let directory = test_directory("reload_runtime_state");
+    let with_etag = |mut response: Response, etag: &str| {
+        response.headers.push(("ETag", format!("\"{}\"", etag)));
+        response
+    };
+    let not_modified = || Response {
+        status:  "304 Not Modified",
+        headers: vec![("ETag", "\"shrunk\"".to_string())],
+        body:    "",
+        hang:    None,
+    };
+    let server = MetaServer::start(vec![
+        with_etag(Response::ok(META_JSON), "full"),
+        with_etag(
+            Response::ok(r#"{"hooks": ["192.30.252.0/22", "2a0a:a440::/29"]}"#),
+            "shrunk",
+        ),
+        not_modified(),
+        not_modified(),
+    ]);
+    let clock = Arc::new(MockClock::default());
+    let mut hooker = hooker(&directory, &server, "removal_grace_secs = \"1h\"")
+        .with_clock(Arc::clone(&clock) as _);
+
+    hooker.update_cycle(false).unwrap();
+    assert!(hooker.update_cycle(false).unwrap().is_empty());
+
+    let mut hooker = self::hooker(&directory, &server, "removal_grace_secs = \"1h\"")
+        .with_clock(Arc::clone(&clock) as _)
+        .with_runtime_state_of(hooker);
+
+    clock.advance(Duration::from_secs(3599));
+    assert!(hooker.update_cycle(false).unwrap().is_empty());
+
+    clock.advance(Duration::from_secs(1));
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert_eq!(diff.removed.len(), 1);
+
+    let requests = server.finish();
+    assert!(requests[2].contains("if-none-match: \"shrunk\"\r\n"));
+}
+
+#[test]
+fn hook_debounce_runs_hook_once_allow_list_stays_unchanged() {
+    let directory = test_directory("hook_debounce");
+    let server = MetaServer::start(vec![Response::ok(META_JSON), Response::ok(META_JSON)]);
+    let clock = Arc::new(MockClock::default());
+    let mut hooker = hooker(&directory, &server, "hook_debounce_secs = \"5m\"")
+        .with_clock(Arc::clone(&clock) as _);
+
+    hooker.update_cycle(false).unwrap();
+
+    assert!(!directory.join("hook_ran").exists());
+    assert_eq!(hooker.hook_due_in(), Some(Duration::from_secs(300)));
+
+    clock.advance(Duration::from_secs(300));
+    assert_eq!(hooker.hook_due_in(), Some(Duration::ZERO));
+    hooker.update_cycle(false).unwrap();
+
+    assert!(directory.join("hook_ran").exists());
+    assert_eq!(hooker.hook_due_in(), None);
+
+    server.finish();
+}
+
+#[test]
+fn allow_file_v6_gets_ipv6_cidrs_and_is_left_alone_by_ipv4_changes() {
+    let directory = test_directory("allow_file_v6");
+    let server = MetaServer::start(vec![
+        Response::ok(META_JSON),
+        Response::ok(r#"{"hooks": ["192.30.252.0/22", "2a0a:a440::/29"]}"#),
+    ]);
+    let allow_file_v6 = directory.join("allow_v6.conf");
+    let extra = format!("allow_file_v6 = \"{}\"", allow_file_v6.display());
+    let mut hooker = hooker(&directory, &server, &extra);
+
+    hooker.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 185.199.108.0/22;\nallow 192.30.252.0/22;\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&allow_file_v6).unwrap(),
+        "allow 2a0a:a440::/29;\n"
+    );
+    assert_eq!(hooker.allow_list_len(), 3);
+
+    std::fs::remove_file(directory.join("hook_ran")).unwrap();
+    let v6_modified = std::fs::metadata(&allow_file_v6)
+        .unwrap()
+        .modified()
+        .unwrap();
+
+    let diff = hooker.update_cycle(false).unwrap();
+
+    assert_eq!(diff.removed.len(), 1);
+    assert!(diff.added.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 192.30.252.0/22;\n"
+    );
+    assert_eq!(
+        std::fs::metadata(&allow_file_v6)
+            .unwrap()
+            .modified()
+            .unwrap(),
+        v6_modified
+    );
+    hooker.run_pending_hook().unwrap();
+    assert!(directory.join("hook_ran").exists());
+
+    server.finish();
+}
+
+#[test]
+fn include_files_are_reread_every_cycle_and_missing_ones_skipped() {
+    let directory = test_directory("include_files");
+    let server = MetaServer::start(vec![
+        Response::ok(r#"{"hooks": ["192.30.252.0/22"]}"#),
+        Response::ok(r#"{"hooks": ["192.30.252.0/22"]}"#),
+    ]);
+    let include_file = directory.join("partners.txt");
+    std::fs::write(&include_file, "# partner\n203.0.113.0/24\n").unwrap();
+    let extra = format!(
+        "include_files = [\"{}\", \"{}\"]",
+        include_file.display(),
+        directory.join("missing.txt").display()
+    );
+    let mut hooker = hooker(&directory, &server, &extra);
+
+    hooker.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 192.30.252.0/22;\nallow 203.0.113.0/24;\n"
+    );
+
+    std::fs::write(&include_file, "198.51.100.7/32 partner\n").unwrap();
+    hooker.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 192.30.252.0/22;\nallow 198.51.100.7/32;\n"
+    );
+
+    server.finish();
+}
+
+#[test]
+fn private_cidrs_are_written_unless_reject_private() {
+    let meta_json = r#"{"hooks": ["192.30.252.0/22", "10.0.0.0/8"]}"#;
+
+    let directory = test_directory("private_cidrs");
+    let server = MetaServer::start(vec![Response::ok(meta_json)]);
+    hooker(&directory, &server, "").update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 10.0.0.0/8;\nallow 192.30.252.0/22;\n"
+    );
+    server.finish();
+
+    let directory = test_directory("reject_private");
+    let server = MetaServer::start(vec![Response::ok(meta_json)]);
+    hooker(&directory, &server, "reject_private = true")
+        .update_cycle(false)
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "allow 192.30.252.0/22;\n"
+    );
+    server.finish();
+}
+
+#[test]
+fn plain_output_format_writes_bare_cidrs_and_loads_them_back() {
+    let directory = test_directory("plain_output_format");
+    let server = MetaServer::start(vec![Response::ok(META_JSON), Response::ok(META_JSON)]);
+    let mut first = hooker(&directory, &server, "output_format = \"plain\"");
+
+    first.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(directory.join("allow.conf")).unwrap(),
+        "185.199.108.0/22\n192.30.252.0/22\n2a0a:a440::/29\n"
+    );
+
+    let modified = std::fs::metadata(directory.join("allow.conf"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    let mut restarted = hooker(&directory, &server, "output_format = \"plain\"");
+
+    assert!(restarted.update_cycle(false).unwrap().is_empty());
+    assert_eq!(
+        std::fs::metadata(directory.join("allow.conf"))
+            .unwrap()
+            .modified()
+            .unwrap(),
+        modified
+    );
+
+    server.finish();
+}
+
+#[test]
+fn always_run_hook_runs_hook_when_nothing_changed() {
+    let directory = test_directory("always_run_hook");
+    let server = MetaServer::start(vec![Response::ok(META_JSON), Response::ok(META_JSON)]);
+    let mut hooker = hooker(&directory, &server, "always_run_hook = true");
+
+    hooker.update_cycle(false).unwrap();
+    std::fs::remove_file(directory.join("hook_ran")).unwrap();
+
+    assert!(hooker.update_cycle(false).unwrap().is_empty());
+    assert!(directory.join("hook_ran").exists());
+
+    server.finish();
+}
+
+#[test]
+fn always_run_hook_gets_changes_postponed_by_hook_debounce() {
+    let directory = test_directory("always_run_hook_debounce");
+    let hook_env = directory.join("hook_env");
+    let server = MetaServer::start(vec![Response::ok(META_JSON), Response::ok(META_JSON)]);
+    let config_path = directory.join("hooker.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "token = \"test\"\nallow_file = \"{}\"\nafter_update_hook = \"echo $HOOKER_CHANGED \
+             $HOOKER_ADDED > {}\"\nrepeat = 60\napi_base_url = \"{}\"\nalways_run_hook = \
+             true\nhook_debounce_secs = \"5m\"\n",
+            directory.join("allow.conf").display(),
+            hook_env.display(),
+            server.base_url
+        ),
+    )
+    .unwrap();
+    let clock = Arc::new(MockClock::default());
+    let mut hooker = Hooker::from_config_file(config_path.to_str().unwrap())
+        .unwrap()
+        .with_clock(Arc::clone(&clock) as _);
+
+    hooker.update_cycle(false).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(&hook_env).unwrap(),
+        "true 185.199.108.0/22 192.30.252.0/22 2a0a:a440::/29\n"
+    );
+    assert_eq!(hooker.hook_due_in(), None);
+
+    hooker.update_cycle(false).unwrap();
+
+    assert_eq!(std::fs::read_to_string(&hook_env).unwrap(), "false\n");
+
+    server.finish();
+}
+
+#[test]
+fn dry_run_doesnt_create_allow_file_or_its_directory() {
+    let directory = test_directory("dry_run_read_only");
+    let allow_file = directory.join("nginx").join("allow.conf");
+    let server = MetaServer::start(vec![Response::ok(META_JSON)]);
+    let config_path = directory.join("hooker.toml");
+    std::fs::write(
+        &config_path,
+        format!(
+            "allow_file = \"{}\"\nafter_update_hook = \"true\"\nrepeat = 60\napi_base_url = \
+             \"{}\"\ncreate_parent_dirs = true\n",
+            allow_file.display(),
+            server.base_url
+        ),
+    )
+    .unwrap();
+    let config = Config::read_from_file(config_path.to_str().unwrap()).unwrap();
+    let mut hooker = Hooker::from_config_read_only(config).unwrap();
+
+    let diff = hooker.update_cycle(true).unwrap();
+
+    assert_eq!(diff.added.len(), 3);
+    assert!(!directory.join("nginx").exists());
+
+    server.finish();
+}
@@ -0,0 +1,72 @@
+//! Proxy named in connection errors. Proxy environment variables are process wide, so they're
+//! set in test binary of their own
+
+use github_nginx_hooker::{
+    FetchError,
+    Hooker,
+};
+
+/// URL of local port nothing listens on
+fn closed_url(scheme: &str, host: &str) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+    format!(
+        "{}://{}:{}",
+        scheme,
+        host,
+        listener.local_addr().unwrap().port()
+    )
+}
+
+/// Proxy of connection error fetching `meta_url`
+fn failed_fetch_proxy(name: &str, meta_url: &str) -> Option<String> {
+    let config_path = std::env::temp_dir().join(format!(
+        "github_nginx_hooker_test_proxy_{}_{}.toml",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(
+        &config_path,
+        format!(
+            "token = \"test\"\nallow_file = \"{}.conf\"\nafter_update_hook = \"true\"\nrepeat = \
+             60\nmeta_url = \"{}/meta\"\n",
+            config_path.display(),
+            meta_url
+        ),
+    )
+    .unwrap();
+
+    let mut hooker = Hooker::from_config_file(config_path.to_str().unwrap()).unwrap();
+    let err = hooker.update_cycle(false).unwrap_err();
+    std::fs::remove_file(&config_path).unwrap();
+
+    match err.downcast::<FetchError>().unwrap() {
+        FetchError::Network { proxy, .. } => proxy,
+        err => panic!("unexpected error {:?}", err),
+    }
+}
+
+#[test]
+fn only_proxy_that_applies_to_url_is_named() {
+    let https_proxy = closed_url("http", "127.0.0.1");
+    std::env::set_var(
+        "HTTPS_PROXY",
+        https_proxy.replace("http://", "http://user:password@"),
+    );
+    std::env::remove_var("HTTP_PROXY");
+    std::env::remove_var("http_proxy");
+    std::env::set_var("NO_PROXY", "localhost");
+
+    assert_eq!(
+        failed_fetch_proxy("http", &closed_url("http", "127.0.0.1")),
+        None
+    );
+    assert_eq!(
+        failed_fetch_proxy("https", &closed_url("https", "127.0.0.1")),
+        Some(format!("{}/", https_proxy))
+    );
+    assert_eq!(
+        failed_fetch_proxy("no_proxy", &closed_url("https", "localhost")),
+        None
+    );
+}